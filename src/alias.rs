@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{Context, Result};
+use log::warn;
+
+use crate::upstream::RoutingTable;
+
+/// What an alias resolves to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alias {
+    pub model: String,
+    /// Upstream or pool the request goes to in place of the routes
+    pub upstream: Option<String>,
+}
+
+/// Gateway-level model names, e.g. `default-chat`, that clients use in place
+/// of real ones so that models can be migrated without redeploying them.
+#[derive(Default)]
+pub struct ModelAliases {
+    aliases: HashMap<String, Alias>,
+}
+
+impl ModelAliases {
+    pub fn load(path: &str, routing_table: &RoutingTable) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let alias_file: HashMap<String, String> = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path))?;
+        Ok(Self::from_file(alias_file, routing_table))
+    }
+
+    /// Values are `model` or `model@upstream`. The suffix is only split off
+    /// when it names an upstream or pool, as model versions may contain `@`.
+    fn from_file(alias_file: HashMap<String, String>, routing_table: &RoutingTable) -> Self {
+        let aliases = alias_file
+            .into_iter()
+            .map(|(name, value)| {
+                let alias = match value.rsplit_once('@') {
+                    Some((model, upstream)) if routing_table.pool(upstream).is_some() => Alias {
+                        model: model.to_string(),
+                        upstream: Some(upstream.to_string()),
+                    },
+                    Some((_, upstream)) => {
+                        warn!("Alias {} asks for model {}: {} is not an upstream or pool", name, value, upstream);
+                        Alias {
+                            model: value,
+                            upstream: None,
+                        }
+                    },
+                    None => Alias {
+                        model: value,
+                        upstream: None,
+                    },
+                };
+                (name, alias)
+            })
+            .collect();
+        Self { aliases }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    pub fn resolve(&self, model: &str) -> Option<&Alias> {
+        self.aliases.get(model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Alias, ModelAliases};
+    use crate::upstream::{RoutingTable, Upstream};
+
+    #[test]
+    fn test_resolve() {
+        let routing_table = RoutingTable::single(Upstream::new("ollama", "127.0.0.1", 11434, false));
+        let alias_file = HashMap::from([
+            ("default-chat".to_string(), "gpt-4o-2024-08-06".to_string()),
+            ("claude-sonnet".to_string(), "qwen2.5:1.5b@ollama".to_string()),
+            ("vertex".to_string(), "claude-3-5-sonnet@20240620".to_string()),
+        ]);
+        let aliases = ModelAliases::from_file(alias_file, &routing_table);
+
+        assert_eq!(
+            aliases.resolve("default-chat"),
+            Some(&Alias {
+                model: "gpt-4o-2024-08-06".to_string(),
+                upstream: None,
+            })
+        );
+        assert_eq!(
+            aliases.resolve("claude-sonnet"),
+            Some(&Alias {
+                model: "qwen2.5:1.5b".to_string(),
+                upstream: Some("ollama".to_string()),
+            })
+        );
+        assert_eq!(aliases.resolve("vertex").unwrap().model, "claude-3-5-sonnet@20240620");
+        assert_eq!(aliases.resolve("gpt-4o"), None);
+    }
+}
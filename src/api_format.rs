@@ -0,0 +1,160 @@
+use http::HeaderMap;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Wire format of a chat request, on the client or on the upstream side.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ApiFormat {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    #[serde(rename = "anthropic")]
+    Anthropic,
+}
+
+impl ApiFormat {
+    /// Name understood by `ConverterFactory`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApiFormat::OpenAI => "openai",
+            ApiFormat::Anthropic => "anthropic",
+        }
+    }
+
+    pub fn chat_path(&self) -> &'static str {
+        match self {
+            ApiFormat::OpenAI => "/v1/chat/completions",
+            ApiFormat::Anthropic => "/v1/messages",
+        }
+    }
+
+    /// Detects the format of a chat request. The `anthropic-version` header
+    /// wins, then the path; on other paths a body with fields only one
+    /// format has breaks the tie, then an `x-api-key` header. Anything else
+    /// is taken for OpenAI.
+    pub fn detect(path: &str, headers: &HeaderMap, body: Option<&[u8]>) -> Self {
+        headers.contains_key("anthropic-version")
+            .then_some(ApiFormat::Anthropic)
+            .or_else(|| match path.trim_end_matches('/') {
+                "/v1/messages" => Some(ApiFormat::Anthropic),
+                "/v1/chat/completions" => Some(ApiFormat::OpenAI),
+                _ => None,
+            })
+            .or_else(|| {
+                let body: Value = serde_json::from_slice(body?).ok()?;
+                detect_body(&body)
+            })
+            .or_else(|| {
+                (headers.contains_key("x-api-key") && !headers.contains_key("authorization"))
+                    .then_some(ApiFormat::Anthropic)
+            })
+            .unwrap_or_default()
+    }
+}
+
+const ANTHROPIC_FIELDS: &[&str] = &["system", "stop_sequences"];
+const OPENAI_FIELDS: &[&str] = &[
+    "max_completion_tokens",
+    "n",
+    "response_format",
+    "stream_options",
+    "logprobs",
+    "frequency_penalty",
+    "presence_penalty",
+    "stop",
+];
+const ANTHROPIC_BLOCKS: &[&str] = &["image", "document", "tool_use", "tool_result", "thinking"];
+const OPENAI_PARTS: &[&str] = &["image_url", "input_audio", "file"];
+
+/// Looks for fields, message roles and content types only one of the
+/// formats uses. Simple requests are valid in both and stay undecided.
+fn detect_body(body: &Value) -> Option<ApiFormat> {
+    let object = body.as_object()?;
+    if ANTHROPIC_FIELDS.iter().any(|field| object.contains_key(*field)) {
+        return Some(ApiFormat::Anthropic);
+    }
+    if OPENAI_FIELDS.iter().any(|field| object.contains_key(*field)) {
+        return Some(ApiFormat::OpenAI);
+    }
+
+    let messages = object.get("messages").and_then(Value::as_array).into_iter().flatten();
+    for message in messages {
+        let role = message.get("role").and_then(Value::as_str).unwrap_or_default();
+        if matches!(role, "system" | "developer" | "tool") || message.get("tool_calls").is_some() {
+            return Some(ApiFormat::OpenAI);
+        }
+        let blocks = message.get("content").and_then(Value::as_array).into_iter().flatten();
+        for block in blocks {
+            let kind = block.get("type").and_then(Value::as_str).unwrap_or_default();
+            if ANTHROPIC_BLOCKS.contains(&kind) {
+                return Some(ApiFormat::Anthropic);
+            }
+            if OPENAI_PARTS.contains(&kind) {
+                return Some(ApiFormat::OpenAI);
+            }
+        }
+    }
+
+    let tools = object.get("tools").and_then(Value::as_array).into_iter().flatten();
+    for tool in tools {
+        if tool.get("input_schema").is_some() {
+            return Some(ApiFormat::Anthropic);
+        }
+        if tool.get("function").is_some() {
+            return Some(ApiFormat::OpenAI);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+
+    use super::ApiFormat;
+
+    #[test]
+    fn test_detect_from_body() {
+        let headers = HeaderMap::new();
+        let detect = |body: &str| ApiFormat::detect("/chat", &headers, Some(body.as_bytes()));
+
+        assert_eq!(
+            detect(r#"{"model": "m", "system": "be brief", "messages": [{"role": "user", "content": "hi"}]}"#),
+            ApiFormat::Anthropic
+        );
+        assert_eq!(
+            detect(r#"{"model": "m", "messages": [{"role": "user", "content": [{"type": "tool_result", "tool_use_id": "1"}]}]}"#),
+            ApiFormat::Anthropic
+        );
+        assert_eq!(
+            detect(r#"{"model": "m", "messages": [{"role": "system", "content": "be brief"}]}"#),
+            ApiFormat::OpenAI
+        );
+        // Both formats accept top_k
+        assert_eq!(
+            detect(r#"{"model": "m", "top_k": 5, "messages": [{"role": "user", "content": "hi"}]}"#),
+            ApiFormat::OpenAI
+        );
+
+        // The body only breaks ties, known paths decide
+        let system = r#"{"model": "m", "system": "be brief", "messages": [{"role": "user", "content": "hi"}]}"#;
+        let path = |path| ApiFormat::detect(path, &headers, Some(system.as_bytes()));
+        assert_eq!(path("/v1/chat/completions"), ApiFormat::OpenAI);
+        assert_eq!(path("/v1/messages"), ApiFormat::Anthropic);
+    }
+
+    #[test]
+    fn test_detect_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(ApiFormat::detect("/chat", &headers, None), ApiFormat::OpenAI);
+
+        headers.insert("x-api-key", HeaderValue::from_static("sk-ant"));
+        assert_eq!(ApiFormat::detect("/chat", &headers, None), ApiFormat::Anthropic);
+        assert_eq!(ApiFormat::detect("/v1/chat/completions", &headers, None), ApiFormat::OpenAI);
+
+        headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
+        assert_eq!(ApiFormat::detect("/v1/chat/completions", &headers, None), ApiFormat::Anthropic);
+        let openai = r#"{"model": "m", "messages": [{"role": "system", "content": "be brief"}]}"#;
+        assert_eq!(ApiFormat::detect("/chat", &headers, Some(openai.as_bytes())), ApiFormat::Anthropic);
+    }
+}
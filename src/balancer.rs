@@ -0,0 +1,305 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::HeaderMap;
+use serde::Deserialize;
+
+use crate::upstream::Upstream;
+
+/// How a pool spreads requests over its members.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Smooth weighted round-robin, as in nginx
+    #[default]
+    WeightedRoundRobin,
+    /// Fewest tokens in flight relative to the weight
+    LeastOutstandingTokens,
+    /// Lowest moving average of the time to response headers
+    LeastLatency,
+}
+
+/// Remaining quota reported without a reset time is trusted for this long.
+const DEFAULT_QUOTA_RESET: Duration = Duration::from_secs(60);
+/// Weight of the latest sample in the latency moving average
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+#[derive(Default)]
+struct MemberState {
+    /// Smooth weighted round-robin counter
+    current_weight: i64,
+    outstanding_tokens: u64,
+    latency_ms: Option<f64>,
+    remaining_requests: Option<u64>,
+    remaining_tokens: Option<u64>,
+    /// When the remaining quota above stops applying
+    quota_reset_at: Option<Instant>,
+}
+
+impl MemberState {
+    fn has_quota(&self, tokens: u64, now: Instant) -> bool {
+        if self.quota_reset_at.is_none_or(|reset_at| reset_at <= now) {
+            return true;
+        }
+        self.remaining_requests != Some(0) && self.remaining_tokens.is_none_or(|remaining| remaining >= tokens)
+    }
+}
+
+pub struct Member {
+    pub upstream: Arc<Upstream>,
+    pub weight: u32,
+}
+
+/// Upstreams serving the same models, e.g. several deployments or API keys
+/// with their own quotas, that requests are balanced over.
+pub struct Pool {
+    pub name: String,
+    strategy: Strategy,
+    members: Vec<Member>,
+    state: Mutex<Vec<MemberState>>,
+}
+
+impl Pool {
+    pub fn new(name: &str, strategy: Strategy, members: Vec<Member>) -> Self {
+        let state = members.iter().map(|_| MemberState::default()).collect();
+        Self {
+            name: name.to_string(),
+            strategy,
+            members,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// A pool of one upstream, named after it.
+    pub fn single(upstream: Arc<Upstream>) -> Self {
+        let name = upstream.name.clone();
+        Self::new(&name, Strategy::default(), vec![Member { upstream, weight: 1 }])
+    }
+
+    pub fn member_count(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn upstream(&self, member: usize) -> &Arc<Upstream> {
+        &self.members[member].upstream
+    }
+
+    /// Picks the member for a request of `tokens`. Members in `exclude`,
+    /// which already failed the request, and members whose reported quota
+    /// cannot fit it are only picked when no other member is left.
+    pub fn select(&self, exclude: &[usize], tokens: u64) -> usize {
+        self.select_at(exclude, tokens, Instant::now())
+    }
+
+    fn select_at(&self, exclude: &[usize], tokens: u64, now: Instant) -> usize {
+        if self.members.len() == 1 {
+            return 0;
+        }
+        let mut state = self.state.lock().unwrap();
+        let all: Vec<usize> = (0..self.members.len()).collect();
+        let untried: Vec<usize> = all.iter().copied().filter(|i| !exclude.contains(i)).collect();
+        let with_quota: Vec<usize> = untried.iter().copied().filter(|&i| state[i].has_quota(tokens, now)).collect();
+        let eligible = [with_quota, untried, all].into_iter().find(|members| !members.is_empty()).unwrap_or_default();
+
+        let candidates = match self.strategy {
+            Strategy::WeightedRoundRobin => eligible,
+            Strategy::LeastOutstandingTokens => {
+                let load = |i: usize| state[i].outstanding_tokens as f64 / self.members[i].weight.max(1) as f64;
+                let least = eligible.iter().map(|&i| load(i)).fold(f64::INFINITY, f64::min);
+                eligible.into_iter().filter(|&i| load(i) <= least).collect()
+            },
+            Strategy::LeastLatency => {
+                // Members without a sample yet are tried first to get one
+                let latency = |i: usize| state[i].latency_ms.unwrap_or(0.0);
+                let least = eligible.iter().map(|&i| latency(i)).fold(f64::INFINITY, f64::min);
+                eligible.into_iter().filter(|&i| latency(i) <= least).collect()
+            },
+        };
+        // Ties are broken by weighted round-robin
+        self.round_robin(&mut state, &candidates)
+    }
+
+    fn round_robin(&self, state: &mut [MemberState], candidates: &[usize]) -> usize {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for &i in candidates {
+            let weight = self.members[i].weight as i64;
+            state[i].current_weight += weight;
+            total += weight;
+            if best.is_none_or(|best| state[i].current_weight > state[best].current_weight) {
+                best = Some(i);
+            }
+        }
+        let best = best.unwrap_or(0);
+        state[best].current_weight -= total;
+        best
+    }
+
+    /// Counts the tokens of a request sent to `member` until it finishes.
+    pub fn start(&self, member: usize, tokens: u64) {
+        self.state.lock().unwrap()[member].outstanding_tokens += tokens;
+    }
+
+    pub fn finish(&self, member: usize, tokens: u64) {
+        let state = &mut self.state.lock().unwrap()[member];
+        state.outstanding_tokens = state.outstanding_tokens.saturating_sub(tokens);
+    }
+
+    pub fn record_latency(&self, member: usize, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1000.0;
+        let state = &mut self.state.lock().unwrap()[member];
+        state.latency_ms = Some(match state.latency_ms {
+            Some(average) => average + LATENCY_SMOOTHING * (sample - average),
+            None => sample,
+        });
+    }
+
+    /// Tracks the quota left on `member` from OpenAI `x-ratelimit-*` or
+    /// Anthropic `anthropic-ratelimit-*` response headers.
+    pub fn update_quota(&self, member: usize, headers: &HeaderMap) {
+        self.update_quota_at(member, headers, Instant::now());
+    }
+
+    fn update_quota_at(&self, member: usize, headers: &HeaderMap, now: Instant) {
+        let header = |names: &[&str]| {
+            names.iter().find_map(|name| headers.get(*name).and_then(|v| v.to_str().ok()))
+        };
+        let remaining_requests = header(&["x-ratelimit-remaining-requests", "anthropic-ratelimit-requests-remaining"])
+            .and_then(|v| v.parse().ok());
+        let remaining_tokens = header(&["x-ratelimit-remaining-tokens", "anthropic-ratelimit-tokens-remaining"])
+            .and_then(|v| v.parse().ok());
+        if remaining_requests.is_none() && remaining_tokens.is_none() {
+            return;
+        }
+        let reset = [header(&["x-ratelimit-reset-requests"]), header(&["x-ratelimit-reset-tokens"])]
+            .into_iter()
+            .flatten()
+            .filter_map(parse_reset)
+            .max()
+            .unwrap_or(DEFAULT_QUOTA_RESET);
+
+        let state = &mut self.state.lock().unwrap()[member];
+        state.remaining_requests = remaining_requests;
+        state.remaining_tokens = remaining_tokens;
+        state.quota_reset_at = Some(now + reset);
+    }
+
+    /// Takes `member` out of rotation after it answered 429.
+    pub fn exhaust(&self, member: usize, retry_after: Option<Duration>) {
+        let state = &mut self.state.lock().unwrap()[member];
+        state.remaining_requests = Some(0);
+        state.quota_reset_at = Some(Instant::now() + retry_after.unwrap_or(DEFAULT_QUOTA_RESET));
+    }
+}
+
+/// Parses an `x-ratelimit-reset-*` duration such as `20ms`, `1.5s` or `6m0s`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let (number, unit_and_rest) = rest.split_at(split);
+        let number: f64 = number.parse().ok()?;
+        let unit_len = unit_and_rest.find(|c: char| c.is_ascii_digit()).unwrap_or(unit_and_rest.len());
+        let (unit, next) = unit_and_rest.split_at(unit_len);
+        let seconds = match unit {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return None,
+        };
+        total += Duration::from_secs_f64(seconds);
+        rest = next;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use http::{HeaderMap, HeaderValue};
+
+    use super::{parse_reset, Member, Pool, Strategy};
+    use crate::upstream::Upstream;
+
+    fn pool(strategy: Strategy, weights: &[u32]) -> Pool {
+        let members = weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| Member {
+                upstream: Arc::new(Upstream::new(&format!("member{}", i), "127.0.0.1", 8000 + i as u16, false)),
+                weight,
+            })
+            .collect();
+        Pool::new("pool", strategy, members)
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let pool = pool(Strategy::WeightedRoundRobin, &[2, 1]);
+        let picks: Vec<usize> = (0..6).map(|_| pool.select(&[], 0)).collect();
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 4);
+        // Smooth: the heavier member is not picked twice in a row every time
+        assert_eq!(picks[..3], [0, 1, 0]);
+
+        assert_eq!(pool.select(&[0], 0), 1);
+    }
+
+    #[test]
+    fn test_least_outstanding_tokens() {
+        let pool = pool(Strategy::LeastOutstandingTokens, &[1, 1, 2]);
+        pool.start(0, 1000);
+        pool.start(1, 300);
+        pool.start(2, 400);
+        // 400 tokens over weight 2 is the least load
+        assert_eq!(pool.select(&[], 0), 2);
+        pool.finish(0, 1000);
+        assert_eq!(pool.select(&[], 0), 0);
+    }
+
+    #[test]
+    fn test_least_latency() {
+        let pool = pool(Strategy::LeastLatency, &[1, 1]);
+        pool.record_latency(0, Duration::from_millis(300));
+        // Not measured yet
+        assert_eq!(pool.select(&[], 0), 1);
+        pool.record_latency(1, Duration::from_millis(900));
+        assert_eq!(pool.select(&[], 0), 0);
+    }
+
+    #[test]
+    fn test_quota_from_headers() {
+        let pool = pool(Strategy::WeightedRoundRobin, &[1, 1]);
+        let now = Instant::now();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining-requests", HeaderValue::from_static("10"));
+        headers.insert("x-ratelimit-remaining-tokens", HeaderValue::from_static("500"));
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("6m0s"));
+        pool.update_quota_at(0, &headers, now);
+
+        // Too few tokens left on the first member
+        for _ in 0..4 {
+            assert_eq!(pool.select_at(&[], 1000, now), 1);
+        }
+        assert_eq!(pool.select_at(&[1], 1000, now), 0);
+        // Back in rotation once the quota resets
+        let later = now + Duration::from_secs(361);
+        let picks: Vec<usize> = (0..2).map(|_| pool.select_at(&[], 1000, later)).collect();
+        assert!(picks.contains(&0));
+
+        pool.exhaust(1, None);
+        assert_eq!(pool.select(&[], 0), 0);
+    }
+
+    #[test]
+    fn test_parse_reset() {
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_reset("soon"), None);
+    }
+}
@@ -0,0 +1,400 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::media;
+use crate::pattern;
+use crate::tokenizer::Tokenizer;
+
+/// Framing of every message, `<|start|>{role}<|message|>{content}<|end|>`
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Added by a message with a `name`
+const TOKENS_PER_NAME: usize = 1;
+/// Priming of the reply, `<|start|>assistant<|message|>`
+const REPLY_PRIMING_TOKENS: usize = 3;
+
+/// Input audio tokens per second of audio
+const AUDIO_TOKENS_PER_SECOND: f64 = 10.0;
+
+/// Image tiles are squares of this many pixels
+const TILE_SIZE: u32 = 512;
+/// Tiles of images whose dimensions are unknown, the most a `high` detail
+/// image can have once scaled
+const MAX_TILES: u32 = 8;
+
+/// Cost of an image: a base cost, the whole of a `low` detail image, plus
+/// one per 512px tile in `high` detail.
+struct ImageCost {
+    base: usize,
+    per_tile: usize,
+}
+
+/// Image costs by model pattern, the first match wins
+const IMAGE_COSTS: [(&str, ImageCost); 3] = [
+    ("gpt-4o-mini*", ImageCost { base: 2833, per_tile: 5667 }),
+    ("o1*", ImageCost { base: 75, per_tile: 150 }),
+    ("o3*", ImageCost { base: 75, per_tile: 150 }),
+];
+const DEFAULT_IMAGE_COST: ImageCost = ImageCost { base: 85, per_tile: 170 };
+
+/// Overhead of the tool definitions, which the API renders into the system
+/// prompt, as measured in the OpenAI cookbook.
+struct ToolFraming {
+    per_function: usize,
+    properties: usize,
+    per_property: usize,
+    per_enum_item: usize,
+    /// Taken off once per enum
+    enum_discount: usize,
+    end: usize,
+}
+
+const GPT_4O_TOOL_FRAMING: ToolFraming = ToolFraming {
+    per_function: 7,
+    properties: 3,
+    per_property: 3,
+    per_enum_item: 3,
+    enum_discount: 3,
+    end: 12,
+};
+
+const GPT_4_TOOL_FRAMING: ToolFraming = ToolFraming {
+    per_function: 10,
+    ..GPT_4O_TOOL_FRAMING
+};
+
+#[derive(Deserialize, Debug)]
+pub struct Message {
+    #[serde(default)]
+    role: String,
+    /// Absent or null on assistant messages that only call tools
+    #[serde(default)]
+    content: Option<MessageContent>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default)]
+    tool_call_id: Option<String>,
+    /// Legacy form of `tool_calls`
+    #[serde(default)]
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    Refusal { refusal: String },
+    ImageUrl { image_url: ImageUrl },
+    InputAudio { input_audio: InputAudio },
+    /// Parts that are not counted, e.g. files
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImageUrl {
+    /// Remote URL or base64 `data:` URL
+    url: String,
+    #[serde(default)]
+    detail: Detail,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Detail {
+    Low,
+    High,
+    /// Left to the API, which picks `high` for all but small images
+    #[default]
+    Auto,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InputAudio {
+    /// Base64 audio
+    data: String,
+    /// `wav` or `mp3`
+    #[serde(default)]
+    format: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ToolCall {
+    /// Absent on calls of tools other than functions
+    #[serde(default)]
+    function: Option<FunctionCall>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FunctionCall {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    arguments: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Tool {
+    /// Absent on built-in tools, e.g. web search
+    #[serde(default)]
+    pub function: Option<FunctionDefinition>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FunctionDefinition {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    /// JSON schema of the arguments
+    #[serde(default)]
+    parameters: Option<Value>,
+}
+
+/// Counts the prompt tokens of a chat request the way the OpenAI cookbook
+/// does: the content, role and name of every message with their framing,
+/// the tool calls they carry, the tool definitions and the reply priming.
+/// Images and audio are estimated with the cost `model` bills them at.
+pub fn count_prompt<'a>(
+    tokenizer: Tokenizer,
+    model: &str,
+    messages: &[Message],
+    functions: impl IntoIterator<Item = &'a FunctionDefinition>,
+) -> usize {
+    let mut tokens = REPLY_PRIMING_TOKENS;
+    for message in messages {
+        tokens += TOKENS_PER_MESSAGE + tokenizer.count(&message.role);
+        tokens += match &message.content {
+            Some(MessageContent::Text(text)) => tokenizer.count(text),
+            Some(MessageContent::Parts(parts)) => parts.iter().map(|part| part_tokens(tokenizer, model, part)).sum(),
+            None => 0,
+        };
+        if let Some(name) = &message.name {
+            tokens += TOKENS_PER_NAME + tokenizer.count(name);
+        }
+        let calls = message.tool_calls.iter().filter_map(|call| call.function.as_ref()).chain(&message.function_call);
+        for call in calls {
+            tokens += tokenizer.count(&call.name) + tokenizer.count(&call.arguments);
+        }
+        if let Some(id) = &message.tool_call_id {
+            tokens += tokenizer.count(id);
+        }
+    }
+    tokens + tool_tokens(tokenizer, functions)
+}
+
+fn part_tokens(tokenizer: Tokenizer, model: &str, part: &ContentPart) -> usize {
+    match part {
+        ContentPart::Text { text } => tokenizer.count(text),
+        ContentPart::Refusal { refusal } => tokenizer.count(refusal),
+        ContentPart::ImageUrl { image_url } => image_tokens(model, image_url),
+        ContentPart::InputAudio { input_audio } => audio_tokens(input_audio),
+        ContentPart::Other => 0,
+    }
+}
+
+/// Images are scaled to fit in 2048x2048, then down to 768px on their
+/// shortest side, and billed per 512px tile. The dimensions are read from
+/// `data:` URLs; remote images are assumed to take the most tiles.
+fn image_tokens(model: &str, image: &ImageUrl) -> usize {
+    let cost = IMAGE_COSTS
+        .iter()
+        .find(|(pattern, _)| pattern::matches(pattern, model))
+        .map_or(&DEFAULT_IMAGE_COST, |(_, cost)| cost);
+    if image.detail == Detail::Low {
+        return cost.base;
+    }
+    let dimensions = media::decode_data_url(&image.url).and_then(|data| media::image_dimensions(&data));
+    let tiles = dimensions.map_or(MAX_TILES, |(width, height)| tiles(width, height));
+    cost.base + cost.per_tile * tiles as usize
+}
+
+fn tiles(width: u32, height: u32) -> u32 {
+    let (mut width, mut height) = (width as f64, height as f64);
+    let fit = (2048.0 / width.max(height)).min(1.0);
+    let shortest = (768.0 / (width * fit).min(height * fit)).min(1.0);
+    width *= fit * shortest;
+    height *= fit * shortest;
+    (width / TILE_SIZE as f64).ceil() as u32 * (height / TILE_SIZE as f64).ceil() as u32
+}
+
+fn audio_tokens(audio: &InputAudio) -> usize {
+    let Some(data) = media::decode_base64(&audio.data) else {
+        return 0;
+    };
+    (media::audio_duration(&data, &audio.format) * AUDIO_TOKENS_PER_SECOND).ceil() as usize
+}
+
+/// Tool definitions are rendered as `name:description` followed by a
+/// `name:type:description` line per parameter, with their enum values.
+fn tool_tokens<'a>(tokenizer: Tokenizer, functions: impl IntoIterator<Item = &'a FunctionDefinition>) -> usize {
+    let framing = if tokenizer.is_o200k() { GPT_4O_TOOL_FRAMING } else { GPT_4_TOOL_FRAMING };
+
+    let mut tokens = 0;
+    let mut any = false;
+    for function in functions {
+        any = true;
+        tokens += framing.per_function;
+        tokens += tokenizer.count(&format!("{}:{}", function.name, without_period(function.description.as_deref())));
+
+        let properties = function
+            .parameters
+            .as_ref()
+            .and_then(|parameters| parameters["properties"].as_object())
+            .filter(|properties| !properties.is_empty());
+        let Some(properties) = properties else {
+            continue;
+        };
+        tokens += framing.properties;
+        for (key, property) in properties {
+            tokens += framing.per_property;
+            if let Some(items) = property["enum"].as_array() {
+                let enum_tokens: usize = items
+                    .iter()
+                    .map(|item| framing.per_enum_item + tokenizer.count(item.as_str().unwrap_or_default()))
+                    .sum();
+                tokens += enum_tokens.saturating_sub(framing.enum_discount);
+            }
+            let kind = property["type"].as_str().unwrap_or_default();
+            let line = format!("{}:{}:{}", key, kind, without_period(property["description"].as_str()));
+            tokens += tokenizer.count(&line);
+        }
+    }
+    if any {
+        tokens += framing.end;
+    }
+    tokens
+}
+
+fn without_period(description: Option<&str>) -> &str {
+    let description = description.unwrap_or_default();
+    description.strip_suffix('.').unwrap_or(description)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    use super::{count_prompt, tiles, Message, Tool};
+    use crate::tokenizer::{Encoding, Tokenizer, TokenizerRegistry};
+
+    #[derive(Deserialize)]
+    struct Fixture {
+        model: String,
+        request: Value,
+        prompt_tokens: usize,
+    }
+
+    #[derive(Deserialize)]
+    struct Request {
+        messages: Vec<Message>,
+        #[serde(default)]
+        tools: Vec<Tool>,
+    }
+
+    fn count(model: &str, request: Value) -> usize {
+        let request: Request = serde_json::from_value(request).unwrap();
+        let tokenizer = TokenizerRegistry::new(Encoding::Cl100kBase).for_model(model);
+        count_prompt(tokenizer, model, &request.messages, request.tools.iter().filter_map(|tool| tool.function.as_ref()))
+    }
+
+    /// Recorded `usage.prompt_tokens` of real requests
+    #[test]
+    fn test_fixtures() {
+        let fixtures: Vec<Fixture> = serde_json::from_str(include_str!("../fixtures/prompt_tokens.json")).unwrap();
+        for fixture in fixtures {
+            assert_eq!(count(&fixture.model, fixture.request), fixture.prompt_tokens, "{}", fixture.model);
+        }
+    }
+
+    #[test]
+    fn test_content_parts() {
+        let text = count("gpt-4o", serde_json::json!({"messages": [{"role": "user", "content": "Hello there"}]}));
+        let parts = count(
+            "gpt-4o",
+            serde_json::json!({"messages": [{"role": "user", "content": [
+                {"type": "text", "text": "Hello"},
+                {"type": "text", "text": " there"},
+                {"type": "file", "file": {"file_id": "file-1"}}
+            ]}]}),
+        );
+        assert_eq!(parts, text);
+    }
+
+    /// Examples of the OpenAI vision guide
+    #[test]
+    fn test_tiles() {
+        assert_eq!(tiles(1024, 1024), 4);
+        assert_eq!(tiles(2048, 4096), 6);
+        assert_eq!(tiles(512, 512), 1);
+        assert_eq!(tiles(100, 4000), 4);
+    }
+
+    #[test]
+    fn test_images() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(1024u32.to_be_bytes());
+        png.extend(1024u32.to_be_bytes());
+        let url = format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(png));
+        let image = |model: &str, url: &str, detail: &str| {
+            let without = count(model, serde_json::json!({"messages": [{"role": "user", "content": []}]}));
+            let with = count(
+                model,
+                serde_json::json!({"messages": [{"role": "user", "content": [
+                    {"type": "image_url", "image_url": {"url": url, "detail": detail}}
+                ]}]}),
+            );
+            with - without
+        };
+
+        assert_eq!(image("gpt-4o", &url, "high"), 85 + 170 * 4);
+        assert_eq!(image("gpt-4o", &url, "auto"), 85 + 170 * 4);
+        assert_eq!(image("gpt-4o", &url, "low"), 85);
+        assert_eq!(image("gpt-4o-mini", &url, "low"), 2833);
+        assert_eq!(image("o1", &url, "high"), 75 + 150 * 4);
+        assert_eq!(image("gpt-4o", "https://example.com/cat.png", "high"), 85 + 170 * 8);
+    }
+
+    #[test]
+    fn test_audio() {
+        // Three seconds of MPEG-1 layer III at 64 kbit/s
+        let mut mp3 = vec![0xFF, 0xFB, 0x50, 0x00];
+        mp3.resize(24_000, 0);
+        let request = serde_json::json!({"messages": [{"role": "user", "content": [
+            {"type": "input_audio", "input_audio": {"data": general_purpose::STANDARD.encode(mp3), "format": "mp3"}}
+        ]}]});
+        let without = count("gpt-4o-audio-preview", serde_json::json!({"messages": [{"role": "user", "content": []}]}));
+        assert_eq!(count("gpt-4o-audio-preview", request) - without, 30);
+    }
+
+    #[test]
+    fn test_tool_call_messages() {
+        let request = serde_json::json!({"messages": [
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "18C"}
+        ]});
+        let without_calls = serde_json::json!({"messages": [
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant"},
+            {"role": "tool", "content": "18C"}
+        ]});
+        let o200k = Tokenizer::Bpe(tiktoken_rs::o200k_base_singleton());
+        let calls = ["get_weather", "{\"city\":\"Paris\"}", "call_1"].map(|text| o200k.count(text));
+        assert_eq!(count("gpt-4o", request), count("gpt-4o", without_calls) + calls.iter().sum::<usize>());
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use pingora_error::{Error, ErrorType::HTTPStatus};
+use serde_json::json;
+
+use crate::api_format::ApiFormat;
+use crate::http_proxy::LimitUnit;
+
+/// Failures the gateway reports to clients itself. Each one is raised as a
+/// Pingora error carrying it as the cause, so that `fail_to_proxy` can write
+/// the error body in the client's API format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GatewayError {
+    /// The request body could not be parsed
+    InvalidRequest(String),
+    /// No valid user identity and anonymous requests are rejected
+    Unauthorized(String),
+    /// No route matches the request
+    NoUpstream(String),
+    /// The request could not be converted to the upstream's format
+    RequestConversion(String),
+    /// The upstream response could not be parsed or converted
+    ResponseConversion(String),
+    /// A rate limit of the given unit, named after it, is exceeded
+    RateLimited(LimitUnit, String),
+    /// The rate limiter backend failed
+    RateLimiter(String),
+    /// The upstream failed or answered with an error
+    Upstream(u16, String),
+    Internal(String),
+}
+
+impl GatewayError {
+    /// For errors raised by Pingora itself.
+    pub fn from_status(status: u16) -> Self {
+        let message = http::StatusCode::from_u16(status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("Error")
+            .to_string();
+        match status {
+            400 => GatewayError::InvalidRequest(message),
+            500 => GatewayError::Internal(message),
+            _ => GatewayError::Upstream(status, message),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            GatewayError::InvalidRequest(_) | GatewayError::RequestConversion(_) => 400,
+            GatewayError::Unauthorized(_) => 401,
+            GatewayError::NoUpstream(_) => 404,
+            GatewayError::RateLimited(..) => 429,
+            GatewayError::Internal(_) => 500,
+            GatewayError::ResponseConversion(_) | GatewayError::RateLimiter(_) => 502,
+            GatewayError::Upstream(status, _) => *status,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            GatewayError::InvalidRequest(message)
+            | GatewayError::Unauthorized(message)
+            | GatewayError::NoUpstream(message)
+            | GatewayError::RequestConversion(message)
+            | GatewayError::ResponseConversion(message)
+            | GatewayError::RateLimiter(message)
+            | GatewayError::Upstream(_, message)
+            | GatewayError::Internal(message) => message,
+            GatewayError::RateLimited(..) => "Rate limit exceeded",
+        }
+    }
+
+    /// Error `type` and `code` in the OpenAI format. Rate limits are typed
+    /// by what they count, like OpenAI's own; the message names the limit.
+    fn openai_type(&self) -> (&'static str, Option<&'static str>) {
+        match self {
+            GatewayError::RateLimited(LimitUnit::Tokens, _) => ("tokens", Some("rate_limit_exceeded")),
+            GatewayError::RateLimited(LimitUnit::Requests | LimitUnit::Concurrency, _) => {
+                ("requests", Some("rate_limit_exceeded"))
+            },
+            GatewayError::Unauthorized(_) => ("invalid_request_error", Some("invalid_api_key")),
+            GatewayError::NoUpstream(_) => ("invalid_request_error", Some("model_not_found")),
+            GatewayError::InvalidRequest(_) | GatewayError::RequestConversion(_) => ("invalid_request_error", None),
+            _ => ("server_error", None),
+        }
+    }
+
+    /// JSON error body the way the client's API writes it.
+    pub fn body(&self, format: ApiFormat) -> Vec<u8> {
+        let body = match format {
+            ApiFormat::OpenAI => {
+                let (kind, code) = self.openai_type();
+                json!({"error": {"message": self.to_string(), "type": kind, "param": null, "code": code}})
+            },
+            ApiFormat::Anthropic => {
+                json!({"type": "error", "error": {"type": anthropic_error_type(self.status()), "message": self.to_string()}})
+            },
+        };
+        body.to_string().into_bytes()
+    }
+}
+
+/// Error `type` in the Anthropic format, which is keyed on the status.
+pub fn anthropic_error_type(status: u16) -> &'static str {
+    match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GatewayError::RateLimited(_, limit) => write!(f, "Rate limit exceeded: {}", limit),
+            _ => f.write_str(self.message()),
+        }
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+impl From<GatewayError> for Box<Error> {
+    fn from(error: GatewayError) -> Self {
+        Error::because(HTTPStatus(error.status()), error.to_string(), error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::GatewayError;
+    use crate::api_format::ApiFormat;
+    use crate::http_proxy::LimitUnit;
+
+    fn body(error: &GatewayError, format: ApiFormat) -> Value {
+        serde_json::from_slice(&error.body(format)).unwrap()
+    }
+
+    #[test]
+    fn test_error_bodies() {
+        let error = GatewayError::RateLimited(LimitUnit::Tokens, "tokens_per_minute".to_string());
+        assert_eq!(error.status(), 429);
+        assert_eq!(
+            body(&error, ApiFormat::OpenAI),
+            json!({"error": {
+                "message": "Rate limit exceeded: tokens_per_minute",
+                "type": "tokens",
+                "param": null,
+                "code": "rate_limit_exceeded",
+            }})
+        );
+        assert_eq!(
+            body(&error, ApiFormat::Anthropic),
+            json!({"type": "error", "error": {
+                "type": "rate_limit_error",
+                "message": "Rate limit exceeded: tokens_per_minute",
+            }})
+        );
+
+        let error = GatewayError::RateLimited(LimitUnit::Concurrency, "concurrent_requests".to_string());
+        assert_eq!(body(&error, ApiFormat::OpenAI)["error"]["type"], "requests");
+
+        let error = GatewayError::RequestConversion("Unsupported content block".to_string());
+        assert_eq!(error.status(), 400);
+        assert_eq!(body(&error, ApiFormat::Anthropic)["error"]["type"], "invalid_request_error");
+    }
+
+    #[test]
+    fn test_from_status() {
+        assert_eq!(GatewayError::from_status(502), GatewayError::Upstream(502, "Bad Gateway".to_string()));
+        assert_eq!(GatewayError::from_status(400).status(), 400);
+        assert_eq!(body(&GatewayError::from_status(529), ApiFormat::Anthropic)["error"]["type"], "overloaded_error");
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{info, warn};
+use pingora_core::connectors::http::Connector;
+use pingora_core::prelude::HttpPeer;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_http::RequestHeader;
+
+use crate::api_format::ApiFormat;
+use crate::http_proxy::{GatewayMetrics, ANTHROPIC_VERSION};
+use crate::upstream::{RoutingTable, Upstream};
+
+/// Active health checks and the circuit breaker that ejects failing
+/// upstreams, fed by both the checks and the outcome of proxied requests.
+#[derive(clap::Args, Clone, Debug)]
+pub struct HealthConfig {
+    #[arg(long = "health-check-interval-secs", help = "Seconds between active health checks of every upstream, 0 disables them", default_value_t = 30, env = "HEALTH_CHECK_INTERVAL_SECS")]
+    pub interval_secs: u64,
+
+    #[arg(long, help = "Path probed by health checks, mapped onto each upstream like request paths", default_value = "/v1/models", env)]
+    pub health_check_path: String,
+
+    #[arg(long = "health-check-timeout-ms", help = "Timeout of a health check", default_value_t = 5000, env = "HEALTH_CHECK_TIMEOUT_MS")]
+    pub timeout_ms: u64,
+
+    #[arg(long = "health-check-failures", help = "Consecutive failed health checks that eject an upstream", default_value_t = 2, env = "HEALTH_CHECK_FAILURES")]
+    pub unhealthy_threshold: u32,
+
+    #[arg(long = "breaker-window", help = "Recent attempts per upstream the circuit breaker looks at", default_value_t = 20, env = "BREAKER_WINDOW")]
+    pub window: usize,
+
+    #[arg(long = "breaker-min-requests", help = "Attempts in the window before their failure rate can eject an upstream", default_value_t = 10, env = "BREAKER_MIN_REQUESTS")]
+    pub min_requests: usize,
+
+    #[arg(long = "breaker-failure-rate", help = "Share of failed attempts in the window that ejects an upstream", default_value_t = 0.5, env = "BREAKER_FAILURE_RATE")]
+    pub failure_rate: f64,
+
+    #[arg(long = "breaker-cooldown-secs", help = "Seconds an ejected upstream gets no requests before a trial one", default_value_t = 30, env = "BREAKER_COOLDOWN_SECS")]
+    pub cooldown_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakerState {
+    /// Requests go through
+    Closed,
+    /// Ejected until the cooldown is over
+    Open,
+    /// A single trial request decides whether to close or open again
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// Value of the `upstream_circuit_state` gauge
+    pub fn gauge_value(self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        }
+    }
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    /// Recent attempts while closed, true for failures
+    outcomes: VecDeque<bool>,
+    /// End of the cooldown while open
+    open_until: Option<Instant>,
+    trial_in_flight: bool,
+    probe_failures: u32,
+}
+
+/// Circuit breaker of one upstream.
+pub struct CircuitBreaker {
+    config: HealthConfig,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &HealthConfig) -> Self {
+        Self {
+            config: config.clone(),
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                outcomes: VecDeque::new(),
+                open_until: None,
+                trial_in_flight: false,
+                probe_failures: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Whether a request could be sent now, without claiming the trial of
+    /// a half-open breaker.
+    fn available_at(&self, now: Instant) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => inner.open_until.is_none_or(|until| until <= now),
+            BreakerState::HalfOpen => !inner.trial_in_flight,
+        }
+    }
+
+    /// Claims the upstream for a request. Once the cooldown is over, a
+    /// single trial request gets through until its outcome is recorded.
+    fn acquire_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open if inner.open_until.is_none_or(|until| until <= now) => {
+                inner.state = BreakerState::HalfOpen;
+                inner.trial_in_flight = true;
+                true
+            },
+            BreakerState::HalfOpen if !inner.trial_in_flight => {
+                inner.trial_in_flight = true;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Records the outcome of a proxied attempt.
+    fn record_at(&self, failed: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::HalfOpen if failed => self.open(&mut inner, now),
+            BreakerState::HalfOpen => Self::close(&mut inner),
+            BreakerState::Closed => {
+                inner.outcomes.push_back(failed);
+                if inner.outcomes.len() > self.config.window {
+                    inner.outcomes.pop_front();
+                }
+                let attempts = inner.outcomes.len();
+                let failures = inner.outcomes.iter().filter(|&&failed| failed).count();
+                if attempts >= self.config.min_requests.max(1)
+                    && failures as f64 >= self.config.failure_rate * attempts as f64
+                {
+                    self.open(&mut inner, now);
+                }
+            },
+            // Attempts that started before the upstream was ejected
+            BreakerState::Open => {},
+        }
+    }
+
+    /// Gives back the trial of a half-open breaker when its request ended
+    /// before the upstream answered, e.g. because it was rate limited.
+    fn release(&self) {
+        self.inner.lock().unwrap().trial_in_flight = false;
+    }
+
+    /// Records the result of an active health check. A healthy upstream is
+    /// closed again once its cooldown is over, an unhealthy one is ejected
+    /// after enough consecutive failures and kept out while they go on.
+    fn record_probe_at(&self, healthy: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        if healthy {
+            inner.probe_failures = 0;
+            if inner.state == BreakerState::Open && inner.open_until.is_none_or(|until| until <= now) {
+                Self::close(&mut inner);
+            }
+            return;
+        }
+        inner.probe_failures += 1;
+        if inner.state != BreakerState::Closed || inner.probe_failures >= self.config.unhealthy_threshold {
+            self.open(&mut inner, now);
+        }
+    }
+
+    fn open(&self, inner: &mut BreakerInner, now: Instant) {
+        inner.state = BreakerState::Open;
+        inner.open_until = Some(now + Duration::from_secs(self.config.cooldown_secs));
+        inner.outcomes.clear();
+        inner.trial_in_flight = false;
+    }
+
+    fn close(inner: &mut BreakerInner) {
+        inner.state = BreakerState::Closed;
+        inner.open_until = None;
+        inner.outcomes.clear();
+        inner.trial_in_flight = false;
+        inner.probe_failures = 0;
+    }
+}
+
+/// Circuit breakers of every upstream.
+pub struct UpstreamHealth {
+    config: HealthConfig,
+    upstreams: Vec<Arc<Upstream>>,
+    breakers: HashMap<String, CircuitBreaker>,
+    metrics: &'static GatewayMetrics,
+}
+
+impl UpstreamHealth {
+    pub fn new(routing_table: &RoutingTable, config: HealthConfig) -> Self {
+        let metrics = GatewayMetrics::instance();
+        let upstreams = routing_table.upstreams();
+        let breakers = upstreams
+            .iter()
+            .map(|upstream| {
+                metrics.record_circuit_state(&upstream.name, BreakerState::Closed);
+                (upstream.name.clone(), CircuitBreaker::new(&config))
+            })
+            .collect();
+        Self {
+            config,
+            upstreams,
+            breakers,
+            metrics,
+        }
+    }
+
+    /// Server errors, Anthropic's 529 included, count as failures. Rate
+    /// limits do not, the balancer already steers around them.
+    pub fn failed_status(status: u16) -> bool {
+        status >= 500
+    }
+
+    pub fn available(&self, upstream: &Upstream) -> bool {
+        self.breakers.get(&upstream.name).is_none_or(|breaker| breaker.available_at(Instant::now()))
+    }
+
+    pub fn acquire(&self, upstream: &Upstream) -> bool {
+        self.update(upstream, |breaker, now| breaker.acquire_at(now)).unwrap_or(true)
+    }
+
+    pub fn record(&self, upstream: &Upstream, failed: bool) {
+        self.update(upstream, |breaker, now| breaker.record_at(failed, now));
+    }
+
+    pub fn release(&self, upstream: &Upstream) {
+        self.update(upstream, |breaker, _| breaker.release());
+    }
+
+    fn record_probe(&self, upstream: &Upstream, healthy: bool) {
+        self.metrics.record_health_check(&upstream.name, healthy);
+        self.update(upstream, |breaker, now| breaker.record_probe_at(healthy, now));
+    }
+
+    /// Runs `f` on the upstream's breaker and reports a change of state.
+    fn update<T>(&self, upstream: &Upstream, f: impl FnOnce(&CircuitBreaker, Instant) -> T) -> Option<T> {
+        let breaker = self.breakers.get(&upstream.name)?;
+        let before = breaker.state();
+        let result = f(breaker, Instant::now());
+        let after = breaker.state();
+        if after != before {
+            self.metrics.record_circuit_state(&upstream.name, after);
+            match after {
+                BreakerState::Open => warn!("Upstream {} ejected for {}s", upstream.name, self.config.cooldown_secs),
+                BreakerState::HalfOpen => info!("Upstream {} half-open, sending a trial request", upstream.name),
+                BreakerState::Closed => info!("Upstream {} is back in rotation", upstream.name),
+            }
+        }
+        Some(result)
+    }
+}
+
+/// Background service probing every upstream with a cheap request, by
+/// default `GET /v1/models`. Upstreams that answer, even with a client
+/// error, are healthy; connection failures, timeouts and server errors are
+/// not.
+pub struct HealthChecker {
+    health: Arc<UpstreamHealth>,
+    connector: Connector,
+}
+
+impl HealthChecker {
+    pub fn new(health: Arc<UpstreamHealth>) -> Self {
+        Self {
+            health,
+            connector: Connector::new(None),
+        }
+    }
+
+    async fn probe(&self, upstream: &Upstream) -> bool {
+        let timeout = Duration::from_millis(self.health.config.timeout_ms);
+        match tokio::time::timeout(timeout, self.request(upstream)).await {
+            Ok(Ok(status)) => !UpstreamHealth::failed_status(status),
+            Ok(Err(e)) => {
+                warn!("Health check of upstream {} failed: {}", upstream.name, e);
+                false
+            },
+            Err(_) => {
+                warn!("Health check of upstream {} timed out", upstream.name);
+                false
+            },
+        }
+    }
+
+    async fn request(&self, upstream: &Upstream) -> pingora_error::Result<u16> {
+        let peer = HttpPeer::new((upstream.host.as_str(), upstream.port()), upstream.tls, upstream.sni().to_string());
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+
+        let path = upstream.path_and_query(&self.health.config.health_check_path, None);
+        let mut request = RequestHeader::build("GET", path.as_bytes(), None)?;
+        request.insert_header("Host", upstream.authority())?;
+        if upstream.format == ApiFormat::Anthropic {
+            request.insert_header("anthropic-version", ANTHROPIC_VERSION)?;
+        }
+        if let Some(auth) = &upstream.auth {
+            let (name, value) = auth.header();
+            request.insert_header(name.to_string(), value)?;
+        }
+        session.write_request_header(Box::new(request)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        Ok(session.response_header().map_or(502, |response| response.status.as_u16()))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HealthChecker {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let interval = Duration::from_secs(self.health.config.interval_secs);
+        loop {
+            // Probed together so that a slow upstream does not delay the others
+            let probes = self.health.upstreams.iter().map(|upstream| async move {
+                let healthy = self.probe(upstream).await;
+                self.health.record_probe(upstream, healthy);
+            });
+            join_all(probes).await;
+            if tokio::time::timeout(interval, shutdown.changed()).await.is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{BreakerState, CircuitBreaker, HealthConfig};
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&HealthConfig {
+            interval_secs: 30,
+            health_check_path: "/v1/models".to_string(),
+            timeout_ms: 5000,
+            unhealthy_threshold: 2,
+            window: 4,
+            min_requests: 4,
+            failure_rate: 0.5,
+            cooldown_secs: 30,
+        })
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for failed in [true, false, false] {
+            breaker.record_at(failed, now);
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        // Failures pushed out of the window no longer count
+        breaker.record_at(false, now);
+        breaker.record_at(true, now);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_at(true, now);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.acquire_at(now));
+
+        // A single trial once the cooldown is over
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.available_at(later));
+        assert!(breaker.acquire_at(later));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.available_at(later));
+        assert!(!breaker.acquire_at(later));
+        breaker.record_at(true, later);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        let later = later + Duration::from_secs(30);
+        assert!(breaker.acquire_at(later));
+        breaker.release();
+        assert!(breaker.acquire_at(later));
+        breaker.record_at(false, later);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.acquire_at(later));
+    }
+
+    #[test]
+    fn test_health_checks() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_probe_at(false, now);
+        breaker.record_probe_at(true, now);
+        breaker.record_probe_at(false, now);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_probe_at(false, now);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Failing checks keep it ejected past the cooldown
+        let later = now + Duration::from_secs(20);
+        breaker.record_probe_at(false, later);
+        assert!(!breaker.available_at(now + Duration::from_secs(40)));
+        breaker.record_probe_at(true, now + Duration::from_secs(40));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        breaker.record_probe_at(true, now + Duration::from_secs(50));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
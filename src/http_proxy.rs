@@ -1,73 +1,282 @@
-use std::sync::OnceLock;
-use std::time::Duration;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::Result as AnyResult;
 use async_trait::async_trait;
 use bytes::Bytes;
 use http::Uri;
-use log::info;
+use log::{debug, info, warn};
 use pingora::prelude::{ProxyHttp, Session};
+use pingora::proxy::FailToProxy;
 use pingora_core::prelude::HttpPeer;
-use pingora_error::{Error, ErrorType::HTTPStatus};
+use pingora_error::{Error, ErrorSource, ErrorType::{self, HTTPStatus}};
 use pingora_http::{RequestHeader, ResponseHeader};
-use prometheus::{register_counter_vec, register_int_counter, CounterVec, IntCounter};
+use prometheus::{
+    register_counter_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge_vec, CounterVec, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+};
 use serde::{Deserialize, Deserializer};
 use serde_json::from_slice;
-use tiktoken_rs::CoreBPE;
+use sha1_smol::Sha1;
 
-use ai_api_converter::{anthropic_converter, utils::OpenAIStreamParser, AnthropicConverter, BaseConverter, ConversionResult, ConverterFactory};
-use crate::rate_limiter::SlidingWindowRateLimiter;
+use ai_api_converter::{BaseConverter, ConversionResult, ConverterFactory};
+use crate::alias::ModelAliases;
+use crate::api_format::ApiFormat;
+use crate::chat_tokens::{self, FunctionDefinition, Message, Tool};
+use crate::error::GatewayError;
+use crate::health::{BreakerState, UpstreamHealth};
+use crate::limiter::{Algorithm, LocalRateLimiter};
+use crate::policy::RateLimitPolicies;
+use crate::rate_limiter::{Reservation, SlidingWindowRateLimiter, WindowState};
+use crate::response_conversion::{convert_error, convert_response};
+use crate::retry::RetryPolicy;
+use crate::stream_conversion::StreamConverter;
+use crate::tokenizer::TokenizerRegistry;
+use crate::upstream::{RoutingTable, Target, Upstream};
 
 const USER_RESOURCE: &str = "user";
+/// Subject shared by requests that cannot be told apart
+const ANONYMOUS_USER: &str = "anonymous";
+/// Prefixes of the subjects the anonymous policy derives from the client
+const ANONYMOUS_PREFIXES: [&str; 2] = ["ip:", "key:"];
+const MAX_USER_LEN: usize = 256;
+/// Sent to Anthropic upstreams when a converted request has no version
+pub(crate) const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Pingora replays at most this much of a request body to the upstream, so
+/// larger bodies cannot be read before the upstream is picked.
+const RETRY_BUFFER_LIMIT: usize = 64 * 1024;
 
 // Configurations
 pub struct HttpGatewayConfig<R: SlidingWindowRateLimiter + Send + Sync> {
-    pub openai_config: OpenAIConfig,
-    pub tokenizer: CoreBPE,
+    pub routing_table: RoutingTable,
+    pub aliases: ModelAliases,
+    pub tokenizers: TokenizerRegistry,
     pub sliding_window_rate_limiter: R,
     pub rate_limiting_config: RateLimitingConfig,
+    pub retry_policy: RetryPolicy,
+    pub health: Arc<UpstreamHealth>,
 }
 
 pub struct RateLimitingConfig {
-    pub window_duration_min: u64,
-    pub max_prompt_tokens: u64,
+    pub enabled: bool,
+    /// Every limit is enforced for each subject; the first one hit rejects
+    pub limits: Vec<RateLimit>,
+    /// Per user/group and model overrides of `limits`
+    pub policies: RateLimitPolicies,
     pub user_header_key: &'static str,
+    pub anonymous_policy: AnonymousPolicy,
+    /// Completion tokens reserved for requests that do not set `max_tokens`
+    pub default_completion_tokens: u64,
 }
 
-pub struct OpenAIConfig {
-    pub tls: bool,
-    pub port: u16,
-    pub domain: &'static str,
+/// How requests without a valid user header are identified.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AnonymousPolicy {
+    /// Reject with 401
+    Reject,
+    /// Use the client IP address, `ip:<addr>`
+    ClientIp,
+    /// Use a hash of the API key, `key:<hash>`
+    ApiKey,
+    /// Share the `anonymous` subject, whose quota can be set by a policy
+    Anonymous,
+}
+
+impl AnonymousPolicy {
+    /// Subject for a request without a valid user, or `None` when such
+    /// requests are rejected. Falls back to `anonymous` when the IP or key
+    /// is not available.
+    fn subject(&self, client_ip: Option<IpAddr>, api_key: Option<&str>) -> Option<String> {
+        let subject = match self {
+            AnonymousPolicy::Reject => return None,
+            AnonymousPolicy::ClientIp => client_ip.map(|ip| format!("ip:{}", ip)),
+            AnonymousPolicy::ApiKey => api_key
+                .filter(|key| !key.is_empty())
+                .map(|key| format!("key:{}", &Sha1::from(key).digest().to_string()[..16])),
+            AnonymousPolicy::Anonymous => None,
+        };
+        Some(subject.unwrap_or_else(|| ANONYMOUS_USER.to_string()))
+    }
+}
+
+/// User ids end up in rate limiter keys and metric labels as they are.
+/// Subjects of the anonymous policy are refused, so that a client cannot
+/// spend the quota of another one by naming it.
+fn valid_user(user: &str) -> bool {
+    !user.is_empty()
+        && user.len() <= MAX_USER_LEN
+        && user.bytes().all(|b| b.is_ascii_graphic())
+        && user != ANONYMOUS_USER
+        && !ANONYMOUS_PREFIXES.iter().any(|prefix| user.starts_with(prefix))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitUnit {
+    Tokens,
+    Requests,
+    /// Requests in flight at the same time
+    Concurrency,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Identifies the limit in rate limiter keys and 429 reasons
+    pub name: String,
+    pub unit: LimitUnit,
+    pub max: u64,
+    pub window: Duration,
+    pub algorithm: Algorithm,
+}
+
+impl RateLimit {
+    /// In-flight slots are leased for this long so that a crashed gateway
+    /// cannot hold them forever.
+    const CONCURRENCY_LEASE: Duration = Duration::from_secs(600);
+
+    pub fn tokens(name: &str, max: u64, window: Duration) -> Self {
+        Self { name: name.to_string(), unit: LimitUnit::Tokens, max, window, algorithm: Algorithm::default() }
+    }
+
+    pub fn requests(name: &str, max: u64, window: Duration) -> Self {
+        Self { name: name.to_string(), unit: LimitUnit::Requests, max, window, algorithm: Algorithm::default() }
+    }
+
+    pub fn concurrency(name: &str, max: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            unit: LimitUnit::Concurrency,
+            max,
+            window: Self::CONCURRENCY_LEASE,
+            algorithm: Algorithm::default(),
+        }
+    }
+
+    /// Concurrency limits are leases and always stay on the sliding window.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        if self.unit != LimitUnit::Concurrency {
+            self.algorithm = algorithm;
+        }
+        self
+    }
+
+    /// Most units the limit admits at once; the burst size for bucket
+    /// algorithms and `max` otherwise.
+    pub fn capacity(&self) -> u64 {
+        match self.algorithm {
+            Algorithm::TokenBucket { burst } | Algorithm::Gcra { burst } => burst.unwrap_or(self.max),
+            Algorithm::SlidingWindow | Algorithm::FixedWindow => self.max,
+        }
+    }
 }
 
 // Main gateway struct
 pub struct HttpGateway<R: SlidingWindowRateLimiter + Send + Sync> {
-    tokenizer: CoreBPE,
+    tokenizers: TokenizerRegistry,
     metrics: &'static GatewayMetrics,
-    peer: Peer,
+    routing_table: RoutingTable,
+    aliases: ModelAliases,
     rate_limiter: R,
+    /// Limits using an algorithm other than the sliding window
+    local_limiter: LocalRateLimiter,
     rate_config: RateLimitingConfig,
-}
-
-struct Peer {
-    tls: bool,
-    addr: &'static str,
-    port: u16,
+    retry_policy: RetryPolicy,
+    health: Arc<UpstreamHealth>,
 }
 
 // Context for request processing
 pub struct Ctx {
+    /// Request body as the client sent it
     req_buffer: Vec<u8>,
+    /// The whole body is in `req_buffer`, read ahead of routing or by an
+    /// earlier attempt, and Pingora replays it
+    body_buffered: bool,
+    /// Where the request may go, the route's upstream first
+    targets: Vec<Target>,
+    /// Model the client asked for when an alias replaced it in `req_buffer`
+    requested_model: Option<String>,
+    /// Index in `targets` of the current attempt
+    target: usize,
+    /// Member of the target's pool the current attempt goes to
+    member: usize,
+    /// Members of the target's pool that failed this request
+    failed_members: Vec<usize>,
+    /// Tokens counted as in flight on the member
+    outstanding_tokens: u64,
+    attempt_started: Option<Instant>,
+    /// The attempt's outcome is still to be fed to the circuit breaker
+    attempt_pending: bool,
+    upstream: Option<Arc<Upstream>>,
+    retries: usize,
+    /// `Retry-After` of the last failed attempt
+    retry_after: Option<Duration>,
+    /// Response headers went to the client, so the request cannot be retried
+    response_started: bool,
     resp_buffer: Vec<u8>,
     openai_request: Option<OpenAIRequest>,
+    usage: Option<TokenUsage>,
+    /// Prompt tokens of the upstream's own usage, when it reported any
+    reported_prompt_tokens: Option<u64>,
+    limits: Vec<RateLimit>,
+    reservations: Vec<(RateLimit, Reservation)>,
+    rate_limited: Option<(RateLimit, WindowState)>,
     user: String,
+    endpoint: Endpoint,
+    /// Format the client speaks, only detected for chat requests
+    inbound_format: ApiFormat,
+    /// Upstream format when it differs from the client's
+    convert_to: Option<ApiFormat>,
+    /// Converts a streamed response back to the client's format
+    stream_converter: Option<StreamConverter>,
+    /// Converts a streamed Anthropic response to OpenAI chunks for counting
+    usage_stream: Option<StreamConverter>,
+    /// A non-streamed response is held back and converted once complete
+    convert_response: bool,
+    /// Status of an upstream error response, whose body is passed through
+    upstream_error: Option<u16>,
 }
 
 #[derive(Clone)]
 struct OpenAIRequest {
+    /// Model the request is served with
     model: String,
+    /// Model the client asked for, an alias of `model` or the same
+    requested_model: String,
+    endpoint: Endpoint,
     request_type: RequestType,
     prompt_tokens: u64,
+    max_tokens: Option<u64>,
+}
+
+/// API endpoint a request is addressed to, which decides how its body is
+/// parsed and its tokens are counted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Endpoint {
+    /// OpenAI chat completions or Anthropic messages
+    Chat,
+    Completions,
+    Embeddings,
+    /// Anything else is passed through untouched
+    Other,
+}
+
+impl Endpoint {
+    fn from_path(path: &str) -> Self {
+        match path.trim_end_matches('/') {
+            "/v1/chat/completions" | "/v1/messages" => Endpoint::Chat,
+            "/v1/completions" => Endpoint::Completions,
+            "/v1/embeddings" => Endpoint::Embeddings,
+            _ => Endpoint::Other,
+        }
+    }
+
+    fn counts_tokens(&self) -> bool {
+        *self != Endpoint::Other
+    }
+
+    fn has_completion(&self) -> bool {
+        !matches!(self, Endpoint::Embeddings | Endpoint::Other)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -84,24 +293,36 @@ struct OpenAIRequestBody {
     stream: bool,
     #[serde(default)]
     messages: Vec<Message>,
+    #[serde(default)]
+    tools: Vec<Tool>,
+    /// Legacy form of `tools`
+    #[serde(default)]
+    functions: Vec<FunctionDefinition>,
     #[serde(default, deserialize_with = "deserialize_prompt")]
     prompt: Option<Vec<String>>,
-}
-
-#[derive(Deserialize, Debug)]
-struct Message {
-    content: String,
+    #[serde(default)]
+    max_tokens: Option<u64>,
+    #[serde(default)]
+    max_completion_tokens: Option<u64>,
+    /// Embeddings input: a string, token ids, or arrays of either
+    #[serde(default)]
+    input: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Usage {
+    #[serde(alias = "input_tokens")]
     prompt_tokens: u64,
+    /// Not reported for embeddings
+    #[serde(default, alias = "output_tokens")]
     completion_tokens: u64,
 }
 
 #[derive(Deserialize, Debug)]
 struct UsageResponse {
-    usage: Usage,
+    /// Left out by some OpenAI-compatible servers
+    #[serde(default)]
+    usage: Option<Usage>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -130,16 +351,23 @@ struct TokenUsage {
 }
 
 // Metrics
-struct GatewayMetrics {
+pub(crate) struct GatewayMetrics {
     prompt_tokens: &'static IntCounter,
     completion_tokens: &'static IntCounter,
     total_tokens: &'static IntCounter,
     tokens_by_model: &'static CounterVec,
     tokens_by_user_model: &'static CounterVec,
+    estimated_prompt_tokens: &'static IntCounterVec,
+    prompt_token_drift: &'static HistogramVec,
+    rate_limiter_errors: &'static IntCounterVec,
+    upstream_errors: &'static IntCounterVec,
+    upstream_retries: &'static IntCounterVec,
+    upstream_circuit_state: &'static IntGaugeVec,
+    upstream_healthy: &'static IntGaugeVec,
 }
 
 impl GatewayMetrics {
-    fn instance() -> &'static Self {
+    pub(crate) fn instance() -> &'static Self {
         static METRICS: OnceLock<GatewayMetrics> = OnceLock::new();
         METRICS.get_or_init(Self::init)
     }
@@ -156,15 +384,41 @@ impl GatewayMetrics {
                 register_int_counter!("tokens_total", "Total tokens").unwrap()
             )),
             tokens_by_model: Box::leak(Box::new(
-                register_counter_vec!("tokens_by_model", "Tokens by model", &["model", "type"]).unwrap()
+                register_counter_vec!("tokens_by_model", "Tokens by model", &["model", "requested_model", "type"]).unwrap()
             )),
             tokens_by_user_model: Box::leak(Box::new(
                 register_counter_vec!("tokens_by_user_model", "Tokens by user and model", &["user", "model", "type"]).unwrap()
             )),
+            estimated_prompt_tokens: Box::leak(Box::new(
+                register_int_counter_vec!("estimated_prompt_tokens_total", "Prompt tokens counted by the gateway", &["model"]).unwrap()
+            )),
+            prompt_token_drift: Box::leak(Box::new(
+                register_histogram_vec!(
+                    "prompt_token_drift",
+                    "Relative error of the prompt token count against the upstream's usage",
+                    &["model"],
+                    vec![-0.5, -0.2, -0.1, -0.05, -0.02, 0.0, 0.02, 0.05, 0.1, 0.2, 0.5]
+                ).unwrap()
+            )),
+            rate_limiter_errors: Box::leak(Box::new(
+                register_int_counter_vec!("rate_limiter_errors_total", "Rate limiter backend failures", &["operation"]).unwrap()
+            )),
+            upstream_errors: Box::leak(Box::new(
+                register_int_counter_vec!("upstream_errors_total", "Error responses from upstreams", &["upstream", "status"]).unwrap()
+            )),
+            upstream_retries: Box::leak(Box::new(
+                register_int_counter_vec!("upstream_retries_total", "Retries after failed upstream attempts", &["upstream"]).unwrap()
+            )),
+            upstream_circuit_state: Box::leak(Box::new(
+                register_int_gauge_vec!("upstream_circuit_state", "Circuit breaker state of upstreams: 0 closed, 1 open, 2 half-open", &["upstream"]).unwrap()
+            )),
+            upstream_healthy: Box::leak(Box::new(
+                register_int_gauge_vec!("upstream_healthy", "Result of the last health check of upstreams", &["upstream"]).unwrap()
+            )),
         }
     }
 
-    fn record(&self, usage: &TokenUsage, model: &str, user: &str) {
+    fn record(&self, usage: &TokenUsage, model: &str, requested_model: &str, user: &str) {
         let total = usage.prompt_tokens + usage.completion_tokens;
         
         // Basic counters
@@ -173,13 +427,42 @@ impl GatewayMetrics {
         self.total_tokens.inc_by(total);
 
         // By model
-        self.tokens_by_model.with_label_values(&[model, "prompt"]).inc_by(usage.prompt_tokens as f64);
-        self.tokens_by_model.with_label_values(&[model, "completion"]).inc_by(usage.completion_tokens as f64);
+        self.tokens_by_model.with_label_values(&[model, requested_model, "prompt"]).inc_by(usage.prompt_tokens as f64);
+        self.tokens_by_model.with_label_values(&[model, requested_model, "completion"]).inc_by(usage.completion_tokens as f64);
 
         // By user and model
         self.tokens_by_user_model.with_label_values(&[user, model, "prompt"]).inc_by(usage.prompt_tokens as f64);
         self.tokens_by_user_model.with_label_values(&[user, model, "completion"]).inc_by(usage.completion_tokens as f64);
     }
+
+    /// Exports the gateway's prompt count, compared to the one the upstream
+    /// reports when the response carries usage.
+    fn record_prompt_estimate(&self, model: &str, estimated: u64, reported: Option<u64>) {
+        self.estimated_prompt_tokens.with_label_values(&[model]).inc_by(estimated);
+        if let Some(drift) = reported.and_then(|reported| prompt_drift(estimated, reported)) {
+            self.prompt_token_drift.with_label_values(&[model]).observe(drift);
+        }
+    }
+
+    fn record_rate_limiter_error(&self, operation: &str) {
+        self.rate_limiter_errors.with_label_values(&[operation]).inc();
+    }
+
+    fn record_upstream_error(&self, upstream: &str, status: u16) {
+        self.upstream_errors.with_label_values(&[upstream, &status.to_string()]).inc();
+    }
+
+    fn record_retry(&self, upstream: &str) {
+        self.upstream_retries.with_label_values(&[upstream]).inc();
+    }
+
+    pub(crate) fn record_circuit_state(&self, upstream: &str, state: BreakerState) {
+        self.upstream_circuit_state.with_label_values(&[upstream]).set(state.gauge_value());
+    }
+
+    pub(crate) fn record_health_check(&self, upstream: &str, healthy: bool) {
+        self.upstream_healthy.with_label_values(&[upstream]).set(healthy as i64);
+    }
 }
 
 // Deserialization helper
@@ -222,64 +505,165 @@ where
     deserializer.deserialize_option(PromptVisitor)
 }
 
+/// The `model` a request body asks for.
+fn body_model(body: &[u8]) -> Option<String> {
+    #[derive(Deserialize)]
+    struct ModelOnly {
+        model: Option<String>,
+    }
+
+    from_slice::<ModelOnly>(body).ok().and_then(|body| body.model)
+}
+
+/// Replaces the `model` of a request body.
+fn with_model(body: &[u8], model: &str) -> pingora_error::Result<Vec<u8>> {
+    let mut json: serde_json::Value = from_slice(body)
+        .map_err(|e| GatewayError::InvalidRequest(format!("Invalid JSON: {}", e)))?;
+    json["model"] = serde_json::Value::String(model.to_string());
+    let body = serde_json::to_vec(&json)
+        .map_err(|e| GatewayError::Internal(format!("JSON serialization error: {}", e)))?;
+    Ok(body)
+}
+
+/// Feeds a response chunk through `converter`, flushing it at the end of
+/// the stream. Chunks may convert to nothing while an event is incomplete.
+fn convert_chunk(converter: &mut StreamConverter, chunk: Option<&Bytes>, end_of_stream: bool) -> Vec<u8> {
+    let mut converted = chunk.map_or_else(Vec::new, |chunk| converter.push(chunk));
+    if end_of_stream {
+        converted.extend(converter.finish());
+    }
+    converted
+}
+
+/// Relative error of an estimated token count, positive when it is over the
+/// reported one.
+fn prompt_drift(estimated: u64, reported: u64) -> Option<f64> {
+    (reported > 0).then(|| (estimated as f64 - reported as f64) / reported as f64)
+}
+
+/// Delay an upstream asks for before the next request, from `retry-after-ms`
+/// (Azure OpenAI) or `retry-after` in seconds.
+fn retry_after(response: &ResponseHeader) -> Option<Duration> {
+    let header = |name: &str| response.headers.get(name).and_then(|v| v.to_str().ok());
+    header("retry-after-ms")
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .or_else(|| header("retry-after").and_then(|v| v.parse().ok()).map(Duration::from_secs))
+}
+
+/// Formats a duration the way OpenAI does in `x-ratelimit-reset-*`, e.g.
+/// `20ms`, `1.5s` or `6m0s`.
+fn format_reset(duration: Duration) -> String {
+    let millis = duration.as_millis() as u64;
+    if millis < 1000 {
+        return format!("{}ms", millis);
+    }
+
+    let (hours, minutes, seconds) = (millis / 3_600_000, millis / 60_000 % 60, millis % 60_000);
+    let mut formatted = String::new();
+    if hours > 0 {
+        formatted.push_str(&format!("{}h", hours));
+    }
+    if hours > 0 || minutes > 0 {
+        formatted.push_str(&format!("{}m", minutes));
+    }
+    if seconds % 1000 == 0 {
+        formatted.push_str(&format!("{}s", seconds / 1000));
+    } else {
+        let fraction = format!("{:03}", seconds % 1000);
+        formatted.push_str(&format!("{}.{}s", seconds / 1000, fraction.trim_end_matches('0')));
+    }
+    formatted
+}
+
 // Implementation
 impl<R: SlidingWindowRateLimiter + Send + Sync> HttpGateway<R> {
     pub fn new(config: HttpGatewayConfig<R>) -> AnyResult<Self> {
         Ok(Self {
-            tokenizer: config.tokenizer,
+            tokenizers: config.tokenizers,
             metrics: GatewayMetrics::instance(),
             rate_limiter: config.sliding_window_rate_limiter,
-            peer: Peer {
-                tls: config.openai_config.tls,
-                addr: config.openai_config.domain,
-                port: config.openai_config.port,
-            },
+            local_limiter: LocalRateLimiter::new(),
+            routing_table: config.routing_table,
+            aliases: config.aliases,
             rate_config: config.rate_limiting_config,
+            retry_policy: config.retry_policy,
+            health: config.health,
         })
     }
 
-    fn calculate_tokens(&self, text: &str) -> usize {
-        self.tokenizer.encode_with_special_tokens(text).len()
+    /// Counts tokens with the tokenizer of `model`.
+    fn calculate_tokens(&self, text: &str, model: &str) -> usize {
+        self.tokenizers.for_model(model).count(text)
     }
 
-    fn parse_request(&self, buffer: &[u8], path: &str) -> pingora_error::Result<OpenAIRequest> {
+    /// Counts embeddings input, where token ids count as they are.
+    fn calculate_input_tokens(&self, input: &serde_json::Value, model: &str) -> usize {
+        match input {
+            serde_json::Value::String(text) => self.calculate_tokens(text, model),
+            serde_json::Value::Number(_) => 1,
+            serde_json::Value::Array(items) => items.iter().map(|item| self.calculate_input_tokens(item, model)).sum(),
+            _ => 0,
+        }
+    }
+
+    fn parse_request(&self, buffer: &[u8], endpoint: Endpoint) -> pingora_error::Result<OpenAIRequest> {
         let body: OpenAIRequestBody = from_slice(buffer)
-            .map_err(|_| Error::explain(HTTPStatus(400), "Invalid request body"))?;
-
-        let (request_type, prompt_tokens) = if body.stream {
-            let tokens = match path {
-                p if p.contains("/chat/completions") => {
-                    body.messages.iter()
-                        .map(|msg| self.calculate_tokens(&msg.content))
-                        .sum::<usize>()
-                },
-                p if p.contains("/completions") => {
-                    body.prompt.as_ref()
-                        .map(|prompts| prompts.iter().map(|p| self.calculate_tokens(p)).sum())
-                        .unwrap_or(0)
-                },
-                _ => 0,
-            };
-            (RequestType::Stream, tokens as u64)
-        } else {
-            (RequestType::NonStream, 0)
+            .map_err(|e| GatewayError::InvalidRequest(format!("Invalid request body: {}", e)))?;
+
+        // Counted for every request so that limits see the prompt before the
+        // response; streamed responses carry no usage and keep the count
+        let prompt_tokens = match endpoint {
+            Endpoint::Chat => {
+                let functions = body.tools.iter().filter_map(|tool| tool.function.as_ref()).chain(&body.functions);
+                chat_tokens::count_prompt(self.tokenizers.for_model(&body.model), &body.model, &body.messages, functions)
+            },
+            Endpoint::Completions => {
+                body.prompt.as_ref()
+                    .map(|prompts| prompts.iter().map(|p| self.calculate_tokens(p, &body.model)).sum())
+                    .unwrap_or(0)
+            },
+            Endpoint::Embeddings => {
+                body.input.as_ref().map_or(0, |input| self.calculate_input_tokens(input, &body.model))
+            },
+            Endpoint::Other => 0,
         };
+        let request_type = if body.stream { RequestType::Stream } else { RequestType::NonStream };
 
         Ok(OpenAIRequest {
+            requested_model: body.model.clone(),
             model: body.model,
+            endpoint,
             request_type,
-            prompt_tokens,
+            prompt_tokens: prompt_tokens as u64,
+            max_tokens: body.max_completion_tokens.or(body.max_tokens),
         })
     }
 
-    fn parse_streaming_response(&self, buffer: &[u8]) -> pingora_error::Result<u64> {
+    /// Converts a chat request body between API formats.
+    async fn convert_request(&self, buffer: &[u8], from: ApiFormat, to: ApiFormat) -> pingora_error::Result<Vec<u8>> {
+        let converter = ConverterFactory::get_converter(from.name())
+            .map_err(|e| GatewayError::Internal(format!("No {} converter: {}", from.name(), e)))?;
+        let json_value: serde_json::Value = serde_json::from_slice(buffer)
+            .map_err(|e| GatewayError::InvalidRequest(format!("Invalid JSON: {}", e)))?;
+        let conversion_result: ConversionResult = converter
+            .convert_request(json_value, to.name(), None)
+            .await
+            .map_err(|e| GatewayError::RequestConversion(format!("Cannot convert request to {}: {}", to.name(), e)))?;
+        let data = conversion_result.data.ok_or_else(|| {
+            GatewayError::RequestConversion(format!("Cannot convert request to {}", to.name()))
+        })?;
+        Ok(serde_json::to_vec(&data)
+            .map_err(|e| GatewayError::Internal(format!("JSON serialization error: {}", e)))?)
+    }
+
+    fn parse_streaming_response(&self, buffer: &[u8], model: &str) -> pingora_error::Result<u64> {
         let responses: Vec<StreamingResponse> = buffer
             .split(|&b| b == b'\n')
             .filter(|line| line.starts_with(b"data: {"))
             .map(|line| &line[6..])
             .filter_map(|line| from_slice(line).ok())
             .collect();
-        let mut final_context = String::new();
         let completion_tokens = responses.iter()
             .flat_map(|resp| &resp.choices)
             .filter_map(|choice| {
@@ -287,30 +671,463 @@ impl<R: SlidingWindowRateLimiter + Send + Sync> HttpGateway<R> {
                     .and_then(|d| d.content.as_ref())
                     .or(choice.text.as_ref())
             })
-            .map(|content| {
-                final_context.push_str(content);
-                self.calculate_tokens(content)
-            })
+            .map(|content| self.calculate_tokens(content, model))
             .sum::<usize>();
-        // println!("Final context: \n{}", final_context);
         Ok(completion_tokens as u64)
     }
 
-    async fn check_rate_limit(&self, user: &str) -> pingora_error::Result<()> {
-        let count = self.rate_limiter
-            .fetch_sliding_window(
-                USER_RESOURCE,
-                user,
-                Duration::from_secs(self.rate_config.window_duration_min * 60),
-            )
-            .await
-            .map_err(|e| Error::explain(HTTPStatus(502), e.to_string()))?;
+    /// Takes the usage of a complete response. Streams are counted here, and
+    /// a response without `usage` is charged the counted prompt alone.
+    fn count_usage(&self, ctx: &mut Ctx) -> pingora_error::Result<()> {
+        let Some(req) = &ctx.openai_request else {
+            return Ok(());
+        };
+        let usage = match req.request_type {
+            RequestType::Stream => {
+                let completion_tokens = self.parse_streaming_response(&ctx.resp_buffer, &req.model)?;
+                TokenUsage {
+                    prompt_tokens: req.prompt_tokens,
+                    completion_tokens,
+                }
+            },
+            RequestType::NonStream => {
+                let response: UsageResponse = from_slice(&ctx.resp_buffer)
+                    .map_err(|e| GatewayError::ResponseConversion(format!("Invalid response: {}", e)))?;
+                match response.usage {
+                    Some(usage) => {
+                        ctx.reported_prompt_tokens = Some(usage.prompt_tokens);
+                        TokenUsage {
+                            prompt_tokens: usage.prompt_tokens,
+                            completion_tokens: usage.completion_tokens,
+                        }
+                    },
+                    None => TokenUsage {
+                        prompt_tokens: req.prompt_tokens,
+                        completion_tokens: 0,
+                    },
+                }
+            },
+        };
+        debug!("Usage: {:?}", usage);
+        // Metrics and rate limiter are updated from the async logging hook
+        ctx.usage = Some(usage);
+        Ok(())
+    }
+
+    /// Takes the user from the configured header, falling back to the
+    /// anonymous policy when it is missing or invalid.
+    fn identify_user(&self, session: &Session) -> pingora_error::Result<String> {
+        let headers = &session.req_header().headers;
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        if let Some(user) = header(self.rate_config.user_header_key).filter(|user| valid_user(user)) {
+            return Ok(user.to_string());
+        }
+
+        let client_ip = session.client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        let api_key = header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .or_else(|| header("x-api-key"));
+        self.rate_config.anonymous_policy
+            .subject(client_ip, api_key)
+            .ok_or_else(|| GatewayError::Unauthorized("Missing or invalid user identity".to_string()).into())
+    }
+
+    /// Reads the request body ahead of routing when a route depends on the
+    /// model, models may be aliased or its tokens are counted, and returns
+    /// the model it asks for. Bodies Pingora could not replay are left alone
+    /// and only match routes without a model.
+    async fn read_model(&self, session: &mut Session, ctx: &mut Ctx) -> pingora_error::Result<Option<String>> {
+        let content_length = session.req_header().headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if !(self.routing_table.routes_by_model() || !self.aliases.is_empty() || ctx.endpoint.counts_tokens())
+            || session.req_header().method != "POST"
+            || content_length.is_none_or(|len| len > RETRY_BUFFER_LIMIT)
+        {
+            return Ok(None);
+        }
+
+        session.enable_retry_buffering();
+        while let Some(chunk) = session.read_request_body().await? {
+            ctx.req_buffer.extend_from_slice(&chunk);
+        }
+        ctx.body_buffered = true;
+        Ok(body_model(&ctx.req_buffer))
+    }
+
+    /// Rewrites the model of a body that was not read ahead when it is an
+    /// alias. The request is already routed, so the upstream of a
+    /// `model@upstream` alias is not used.
+    fn resolve_late_alias(&self, ctx: &mut Ctx) -> pingora_error::Result<()> {
+        let Some(model) = body_model(&ctx.req_buffer) else {
+            return Ok(());
+        };
+        if let Some(alias) = self.aliases.resolve(&model) {
+            ctx.req_buffer = with_model(&ctx.req_buffer, &alias.model)?;
+            ctx.requested_model = Some(model);
+        }
+        Ok(())
+    }
+
+    /// Counts the tokens of the buffered request body and admits the request
+    /// against its limits. Requests read ahead are counted before a member
+    /// is selected, so that members whose quota cannot fit them are skipped;
+    /// retries replay the body of a request that was already admitted.
+    async fn count_request(&self, ctx: &mut Ctx) -> pingora_error::Result<()> {
+        if ctx.openai_request.is_some() {
+            return Ok(());
+        }
+        // Tokens are counted on the OpenAI form of the request
+        let openai_body = match ctx.inbound_format {
+            ApiFormat::OpenAI => None,
+            ApiFormat::Anthropic => {
+                Some(self.convert_request(&ctx.req_buffer, ApiFormat::Anthropic, ApiFormat::OpenAI).await?)
+            },
+        };
+        let mut openai_request = self.parse_request(openai_body.as_deref().unwrap_or(&ctx.req_buffer), ctx.endpoint)?;
+        if let Some(requested_model) = &ctx.requested_model {
+            openai_request.requested_model = requested_model.clone();
+        }
+        self.check_rate_limit(ctx, &openai_request).await?;
+        ctx.openai_request = Some(openai_request);
+        Ok(())
+    }
+
+    /// Makes `targets[index]` the target of the next attempt.
+    fn select_target(ctx: &mut Ctx, index: usize) {
+        ctx.target = index;
+        ctx.failed_members.clear();
+    }
+
+    /// Picks the member of the target's pool the attempt goes to, moving on
+    /// to the next target when every member left is ejected.
+    fn select_member(&self, ctx: &mut Ctx) -> pingora_error::Result<()> {
+        let tokens = ctx.openai_request.as_ref().map_or(0, |req| self.expected_tokens(req));
+        let (member, upstream) = loop {
+            let pool = ctx.targets[ctx.target].pool.clone();
+            let mut exclude = ctx.failed_members.clone();
+            exclude.extend((0..pool.member_count()).filter(|&i| !self.health.available(pool.upstream(i))));
+            if (0..pool.member_count()).all(|i| exclude.contains(&i)) {
+                if ctx.target + 1 < ctx.targets.len() {
+                    Self::select_target(ctx, ctx.target + 1);
+                    continue;
+                }
+                return Err(GatewayError::Upstream(503, format!("No healthy upstream in {}", pool.name)).into());
+            }
+            let member = pool.select(&exclude, tokens);
+            // Another request may have claimed the trial of a half-open upstream
+            if self.health.acquire(pool.upstream(member)) {
+                break (member, pool.upstream(member).clone());
+            }
+        };
+        ctx.member = member;
+        ctx.attempt_pending = true;
+        ctx.convert_to = (ctx.endpoint == Endpoint::Chat && ctx.inbound_format != upstream.format)
+            .then_some(upstream.format);
+        ctx.upstream = Some(upstream);
+        ctx.attempt_started = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Feeds the outcome of the attempt to its upstream's circuit breaker.
+    fn record_attempt(&self, ctx: &mut Ctx, failed: bool) {
+        if let (true, Some(upstream)) = (std::mem::take(&mut ctx.attempt_pending), &ctx.upstream) {
+            self.health.record(upstream, failed);
+        }
+    }
+
+    /// Stops counting the attempt's tokens as in flight on its member.
+    fn finish_attempt(ctx: &mut Ctx) {
+        if let Some(target) = ctx.targets.get(ctx.target) {
+            target.pool.finish(ctx.member, std::mem::take(&mut ctx.outstanding_tokens));
+        }
+    }
+
+    /// Whether the body sent upstream differs from the client's, because it
+    /// is converted or asks for an aliased or the fallback's model. Bodies
+    /// that were not read ahead may ask for an alias, which is only known
+    /// once they are complete.
+    fn rewrites_body(&self, ctx: &Ctx) -> bool {
+        ctx.endpoint.counts_tokens()
+            && (ctx.convert_to.is_some()
+                || ctx.requested_model.is_some()
+                || (!ctx.body_buffered && !self.aliases.is_empty())
+                || ctx.targets.get(ctx.target).is_some_and(|target| target.model.is_some()))
+    }
+
+    /// The client's body, with the target's model, in the upstream's format.
+    async fn upstream_body(&self, ctx: &Ctx) -> pingora_error::Result<Vec<u8>> {
+        let mut body = ctx.req_buffer.clone();
+        if let Some(model) = &ctx.targets[ctx.target].model {
+            body = with_model(&body, model)?;
+        }
+        match ctx.convert_to {
+            Some(format) => self.convert_request(&body, ctx.inbound_format, format).await,
+            None => Ok(body),
+        }
+    }
+
+    /// Retries are only possible until the response starts and while
+    /// Pingora still holds the whole request body.
+    fn can_retry(&self, session: &Session, ctx: &Ctx) -> bool {
+        ctx.retries < self.retry_policy.max_retries
+            && !ctx.response_started
+            && !session.as_ref().retry_buffer_truncated()
+    }
+
+    /// Prepares the next attempt, on another member of the pool or else on
+    /// the route's next fallback if there is one. Returns false when the
+    /// request cannot be retried.
+    fn retry(&self, session: &Session, ctx: &mut Ctx) -> bool {
+        if !self.can_retry(session, ctx) {
+            return false;
+        }
+        if let Some(upstream) = &ctx.upstream {
+            self.metrics.record_retry(&upstream.name);
+        }
+        Self::finish_attempt(ctx);
+        ctx.retries += 1;
+        ctx.failed_members.push(ctx.member);
+        if ctx.failed_members.len() >= ctx.targets[ctx.target].pool.member_count() {
+            if ctx.target + 1 < ctx.targets.len() {
+                Self::select_target(ctx, ctx.target + 1);
+            } else {
+                // Every member failed once, start over on the last target
+                ctx.failed_members.clear();
+            }
+        }
+        // Pingora replays the body read so far from the start
+        if !ctx.body_buffered {
+            ctx.req_buffer.clear();
+        }
+        ctx.resp_buffer.clear();
+        ctx.upstream_error = None;
+        ctx.stream_converter = None;
+        ctx.usage_stream = None;
+        ctx.convert_response = false;
+        true
+    }
+
+    fn limit_resource(limit: &RateLimit) -> String {
+        format!("{}:{}", USER_RESOURCE, limit.name)
+    }
+
+    async fn fetch_window_state(&self, limit: &RateLimit, user: &str) -> pingora_error::Result<WindowState> {
+        let resource = Self::limit_resource(limit);
+        let result = match limit.algorithm {
+            Algorithm::SlidingWindow => {
+                self.rate_limiter.fetch_sliding_window_state(&resource, user, limit.window).await
+            },
+            _ => Ok(self.local_limiter.state(&resource, user, limit)),
+        };
+        result.map_err(|e| {
+                self.metrics.record_rate_limiter_error("fetch");
+                warn!("Failed to fetch {} window for user '{}': {}", limit.name, user, e);
+                GatewayError::RateLimiter(e.to_string()).into()
+            })
+    }
+
+    fn rate_limited(ctx: &mut Ctx, limit: &RateLimit, state: WindowState) -> Box<Error> {
+        ctx.rate_limited = Some((limit.clone(), state));
+        GatewayError::RateLimited(limit.unit, limit.name.clone()).into()
+    }
+
+    /// Limits of the first policy matching the user and model, or the
+    /// gateway-wide ones when no policy matches.
+    fn resolve_limits(&self, user: &str, model: &str) -> Vec<RateLimit> {
+        // The local limiter would enforce its limits even without a backend
+        if !self.rate_config.enabled {
+            return Vec::new();
+        }
+        self.rate_config.policies
+            .resolve(user, model)
+            .unwrap_or(&self.rate_config.limits)
+            .to_vec()
+    }
+
+    /// Admits every request against its request and concurrency limits as
+    /// soon as its headers are in, before any body is read. The model is only
+    /// known when the body was read ahead.
+    async fn check_request_limits(&self, ctx: &mut Ctx, model: Option<&str>) -> pingora_error::Result<()> {
+        ctx.limits = self.resolve_limits(&ctx.user, model.unwrap_or_default());
+        self.reserve_limits(ctx, None).await
+    }
+
+    /// Admits a counted request against the limits of its model. Limits the
+    /// request was admitted against by its headers are kept when they still
+    /// apply and given back otherwise.
+    async fn check_rate_limit(&self, ctx: &mut Ctx, req: &OpenAIRequest) -> pingora_error::Result<()> {
+        let limits = self.resolve_limits(&ctx.user, &req.model);
+        let (kept, stale): (Vec<_>, Vec<_>) = ctx.reservations
+            .drain(..)
+            .partition(|(reserved, _)| limits.iter().any(|limit| limit.name == reserved.name));
+        ctx.reservations = kept;
+        for (limit, reservation) in stale {
+            self.release_reservation(&limit, &reservation, &ctx.user).await;
+        }
+        ctx.limits = limits;
+        self.reserve_limits(ctx, Some(req)).await
+    }
+
+    /// What a request is expected to cost against a limit when it is admitted,
+    /// or `None` for token limits while its tokens are not counted yet.
+    fn admission_cost(&self, limit: &RateLimit, req: Option<&OpenAIRequest>) -> Option<u64> {
+        match limit.unit {
+            LimitUnit::Tokens => req.map(|req| self.expected_tokens(req)),
+            LimitUnit::Requests | LimitUnit::Concurrency => Some(1),
+        }
+    }
+
+    /// Prompt tokens plus the completion tokens the request may use.
+    fn expected_tokens(&self, req: &OpenAIRequest) -> u64 {
+        if !req.endpoint.has_completion() {
+            return req.prompt_tokens;
+        }
+        req.prompt_tokens + req.max_tokens.unwrap_or(self.rate_config.default_completion_tokens)
+    }
+
+    /// Reserves the request against every limit it is not reserved against
+    /// yet, all or nothing, so that concurrent requests see each other before
+    /// any usage is known.
+    async fn reserve_limits(&self, ctx: &mut Ctx, req: Option<&OpenAIRequest>) -> pingora_error::Result<()> {
+        let limits = ctx.limits.clone();
+        for limit in &limits {
+            if ctx.reservations.iter().any(|(reserved, _)| reserved.name == limit.name) {
+                continue;
+            }
+            let Some(cost) = self.admission_cost(limit, req) else {
+                continue;
+            };
+            let resource = Self::limit_resource(limit);
+            let result = match limit.algorithm {
+                Algorithm::SlidingWindow => {
+                    self.rate_limiter.reserve_sliding_window(&resource, &ctx.user, cost, limit.window).await
+                },
+                _ => Ok(self.local_limiter.reserve(&resource, &ctx.user, limit, cost)),
+            };
+            let reservation = match result {
+                Ok(reservation) => reservation,
+                Err(e) => {
+                    self.metrics.record_rate_limiter_error("reserve");
+                    warn!("Failed to reserve {} {} for user '{}': {}", cost, limit.name, ctx.user, e);
+                    self.release_reservations(ctx).await;
+                    return Err(GatewayError::RateLimiter(e.to_string()).into());
+                }
+            };
+
+            let exceeded = reservation.total > limit.capacity();
+            ctx.reservations.push((limit.clone(), reservation));
+            if exceeded {
+                self.release_reservations(ctx).await;
+                let state = self.fetch_window_state(limit, &ctx.user).await.unwrap_or_default();
+                return Err(Self::rate_limited(ctx, limit, state));
+            }
+        }
+        Ok(())
+    }
 
-        if count > self.rate_config.max_prompt_tokens {
-            return Err(Error::explain(HTTPStatus(429), "Rate limit exceeded"));
+    /// Adds the OpenAI-style `x-ratelimit-*` headers for the tightest token
+    /// and request limits.
+    fn insert_rate_limit_headers(
+        &self,
+        response: &mut ResponseHeader,
+        states: &[(RateLimit, WindowState)],
+    ) -> pingora_error::Result<()> {
+        for (unit, suffix) in [(LimitUnit::Tokens, "tokens"), (LimitUnit::Requests, "requests")] {
+            let tightest = states
+                .iter()
+                .filter(|(limit, _)| limit.unit == unit)
+                .min_by_key(|(limit, state)| limit.capacity().saturating_sub(state.total));
+            if let Some((limit, state)) = tightest {
+                response.insert_header(format!("x-ratelimit-limit-{}", suffix), limit.capacity().to_string())?;
+                response.insert_header(
+                    format!("x-ratelimit-remaining-{}", suffix),
+                    limit.capacity().saturating_sub(state.total).to_string(),
+                )?;
+                response.insert_header(format!("x-ratelimit-reset-{}", suffix), format_reset(state.reset))?;
+            }
         }
         Ok(())
     }
+
+    fn rate_limited_response(
+        &self,
+        limit: &RateLimit,
+        state: &WindowState,
+        body: &[u8],
+    ) -> pingora_error::Result<ResponseHeader> {
+        let mut response = ResponseHeader::build(429, Some(6))?;
+        self.insert_rate_limit_headers(&mut response, &[(limit.clone(), state.clone())])?;
+        // In-flight slots free up as soon as any request finishes
+        let retry_after = match limit.unit {
+            LimitUnit::Concurrency => 1,
+            LimitUnit::Tokens | LimitUnit::Requests => state.retry_after.as_millis().div_ceil(1000).max(1),
+        };
+        response.insert_header("Retry-After", retry_after.to_string())?;
+        response.insert_header("Content-Type", "application/json")?;
+        response.insert_header("Content-Length", body.len().to_string())?;
+        Ok(response)
+    }
+
+    fn error_response(code: u16, body: &[u8]) -> pingora_error::Result<ResponseHeader> {
+        let mut response = ResponseHeader::build(code, Some(2))?;
+        response.insert_header("Content-Type", "application/json")?;
+        response.insert_header("Content-Length", body.len().to_string())?;
+        Ok(response)
+    }
+
+    async fn release_reservation(&self, limit: &RateLimit, reservation: &Reservation, user: &str) {
+        let resource = Self::limit_resource(limit);
+        let result = match limit.algorithm {
+            Algorithm::SlidingWindow => {
+                self.rate_limiter.release_sliding_window(&resource, user, reservation, limit.window).await
+            },
+            _ => Ok(self.local_limiter.release(&resource, user, limit, reservation)),
+        };
+        if let Err(e) = result {
+            self.metrics.record_rate_limiter_error("release");
+            warn!("Failed to release {} reserved {} for user '{}': {}", reservation.tokens, limit.name, user, e);
+        }
+    }
+
+    async fn release_reservations(&self, ctx: &mut Ctx) {
+        for (limit, reservation) in ctx.reservations.drain(..) {
+            self.release_reservation(&limit, &reservation, &ctx.user).await;
+        }
+    }
+
+    /// Settles every reservation once the request is over: token reservations
+    /// are replaced by the usage reported upstream, in-flight slots are handed
+    /// back and request counts stay as they are.
+    async fn settle_reservations(&self, ctx: &mut Ctx, usage: Option<&TokenUsage>) {
+        for (limit, reservation) in ctx.reservations.drain(..) {
+            match (limit.unit, usage) {
+                (LimitUnit::Tokens, Some(usage)) => {
+                    let total_tokens = usage.prompt_tokens + usage.completion_tokens;
+                    let resource = Self::limit_resource(&limit);
+                    let result = match limit.algorithm {
+                        Algorithm::SlidingWindow => {
+                            self.rate_limiter
+                                .commit_sliding_window(&resource, &ctx.user, &reservation, total_tokens, limit.window)
+                                .await
+                        },
+                        _ => Ok(self.local_limiter.commit(&resource, &ctx.user, &limit, &reservation, total_tokens)),
+                    };
+                    if let Err(e) = result {
+                        self.metrics.record_rate_limiter_error("commit");
+                        warn!("Failed to commit {} {} for user '{}': {}", total_tokens, limit.name, ctx.user, e);
+                    }
+                },
+                (LimitUnit::Tokens, None) | (LimitUnit::Concurrency, _) => {
+                    self.release_reservation(&limit, &reservation, &ctx.user).await;
+                },
+                (LimitUnit::Requests, _) => {},
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -320,29 +1137,96 @@ impl<R: SlidingWindowRateLimiter + Send + Sync> ProxyHttp for HttpGateway<R> {
     fn new_ctx(&self) -> Self::CTX {
         Ctx {
             req_buffer: Vec::with_capacity(4096),
+            body_buffered: false,
+            requested_model: None,
+            targets: Vec::new(),
+            target: 0,
+            member: 0,
+            failed_members: Vec::new(),
+            outstanding_tokens: 0,
+            attempt_started: None,
+            attempt_pending: false,
+            upstream: None,
+            retries: 0,
+            retry_after: None,
+            response_started: false,
             resp_buffer: Vec::with_capacity(8192),
             openai_request: None,
+            usage: None,
+            reported_prompt_tokens: None,
+            limits: Vec::new(),
+            reservations: Vec::new(),
+            rate_limited: None,
             user: String::new(),
+            endpoint: Endpoint::Other,
+            inbound_format: ApiFormat::OpenAI,
+            convert_to: None,
+            stream_converter: None,
+            usage_stream: None,
+            convert_response: false,
+            upstream_error: None,
         }
     }
 
-    async fn upstream_peer(&self, _: &mut Session, _: &mut Self::CTX) -> pingora_error::Result<Box<HttpPeer>> {
+    async fn upstream_peer(&self, _: &mut Session, ctx: &mut Self::CTX) -> pingora_error::Result<Box<HttpPeer>> {
+        if ctx.retries > 0 {
+            tokio::time::sleep(self.retry_policy.backoff(ctx.retries, ctx.retry_after.take())).await;
+        }
+        self.select_member(ctx)?;
+        let upstream = ctx.upstream.as_ref()
+            .ok_or_else(|| GatewayError::Internal("No upstream selected".to_string()))?;
         let peer = Box::new(HttpPeer::new(
-            (self.peer.addr, self.peer.port),
-            self.peer.tls,
-            self.peer.addr.to_string(),
+            (upstream.host.as_str(), upstream.port()),
+            upstream.tls,
+            upstream.sni().to_string(),
         ));
         Ok(peer)
     }
 
      /// Filters incoming requests
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> pingora_error::Result<bool> {
+        let path = session.req_header().uri.path().to_string();
+        ctx.endpoint = Endpoint::from_path(&path);
+
+        let mut model = self.read_model(session, ctx).await?;
+        // Aliases are resolved before the request is routed and parsed
+        let alias = model.as_deref()
+            .filter(|_| ctx.endpoint.counts_tokens())
+            .and_then(|model| self.aliases.resolve(model))
+            .cloned();
+        if let Some(alias) = &alias {
+            ctx.req_buffer = with_model(&ctx.req_buffer, &alias.model)?;
+            ctx.requested_model = model.replace(alias.model.clone());
+        }
+        if ctx.endpoint == Endpoint::Chat {
+            let body = ctx.body_buffered.then_some(ctx.req_buffer.as_slice());
+            ctx.inbound_format = ApiFormat::detect(&path, &session.req_header().headers, body);
+        }
+        // Identified once the format is known, so that a rejection is written in it
+        ctx.user = self.identify_user(session)?;
 
-        session
-            .req_header_mut()
-            .set_uri(Uri::from_static("/v1/chat/completions"));
-        println!("Modified request URI to /v1/chat/completions");
-        println!("Request Path: {:#?}", session.req_header().headers);
+        ctx.targets = match alias.and_then(|alias| alias.upstream) {
+            Some(upstream) => {
+                let pool = self.routing_table.pool(&upstream)
+                    .ok_or_else(|| GatewayError::Internal(format!("Unknown upstream {}", upstream)))?;
+                vec![Target { pool, model: None }]
+            },
+            None => {
+                let targets = self.routing_table.route(&path, model.as_deref()).ok_or_else(|| {
+                    GatewayError::NoUpstream(format!("No upstream for {} {}", path, model.unwrap_or_default()))
+                })?;
+                targets.to_vec()
+            },
+        };
+        Self::select_target(ctx, 0);
+        self.check_request_limits(ctx, model.as_deref()).await?;
+        if ctx.body_buffered && ctx.endpoint.counts_tokens() {
+            self.count_request(ctx).await?;
+        }
+        if session.req_header().method == "POST" {
+            // Kept so that retries can replay the body
+            session.enable_retry_buffering();
+        }
         Ok(false)
     }
     async fn request_body_filter(
@@ -352,80 +1236,149 @@ impl<R: SlidingWindowRateLimiter + Send + Sync> ProxyHttp for HttpGateway<R> {
         end_of_stream: bool,
         ctx: &mut Self::CTX,
     ) -> pingora_error::Result<()> {
+        // Other endpoints are passed through as they are
+        if !ctx.endpoint.counts_tokens() || session.req_header().method != "POST" {
+            return Ok(());
+        }
         if let Some(b) = body {
-            ctx.req_buffer.extend_from_slice(b);
-        }
-
-        if end_of_stream && session.req_header().method == "POST" {
-            let path = session.req_header().uri.path();
-            ctx.openai_request = Some(self.parse_request(&ctx.req_buffer, path)?);
-            
-
-            
-            let anthropic_converter = ConverterFactory::get_converter("anthropic").unwrap();
-
-            println!("Original request body: {}", String::from_utf8_lossy(&ctx.req_buffer));
-            let json_value: serde_json::Value = serde_json::from_slice(&ctx.req_buffer)
-                .map_err(|e| Error::explain(HTTPStatus(400), format!("Invalid JSON: {}", e)))?;
-            let res: Result<ConversionResult, ai_api_converter::ConversionError> = anthropic_converter
-                .convert_request(json_value, "openai", None).await;
-            // println!("Conversion result: {:#?}", res);
-            match res {
-                Ok(conversion_result) => {
-                    // println!("Converted request: {:?}", conversion_result.data);
-                    let json_str = serde_json::to_string(&conversion_result.data.unwrap())
-                        .map_err(|e| Error::explain(HTTPStatus(500), format!("JSON serialization error: {}", e)))?;
-                    println!("Converted JSON string: {}", json_str);
-                    
-                    session
-                        .req_header_mut()
-                        .insert_header("Content-Type", "application/json")?;
-                    session
-                        .req_header_mut()
-                        .insert_header("Content-Length", json_str.len().to_string())?;
-                    println!("session req header: {:#?}", session.req_header().headers);
-                    *body = Some(Bytes::from(json_str));
-                },
-                Err(_) => todo!(),
+            // A body read ahead of routing or by an earlier attempt is already buffered
+            if !ctx.body_buffered {
+                ctx.req_buffer.extend_from_slice(b);
             }
-
         }
+        let rewrite = self.rewrites_body(ctx);
+        if !end_of_stream {
+            // A rewritten body is sent in one piece once it is complete
+            if rewrite {
+                *body = None;
+            }
+            return Ok(());
+        }
+        if !ctx.body_buffered && !self.aliases.is_empty() {
+            self.resolve_late_alias(ctx)?;
+        }
+        ctx.body_buffered = true;
+
+        self.count_request(ctx).await?;
 
+        if let Some(req) = &ctx.openai_request {
+            ctx.outstanding_tokens = self.expected_tokens(req);
+            ctx.targets[ctx.target].pool.start(ctx.member, ctx.outstanding_tokens);
+        }
 
+        if rewrite {
+            *body = Some(Bytes::from(self.upstream_body(ctx).await?));
+        }
         Ok(())
     }
 
     async fn upstream_request_filter(
         &self,
-        session: &mut Session,
+        _: &mut Session,
         upstream_request: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> pingora_error::Result<()> {
-        upstream_request.insert_header("Host", self.peer.addr)?;
-        upstream_request.insert_header("Content-Type", "application/json")?;
-
-        ctx.user = session.req_header().headers
-            .get(self.rate_config.user_header_key)
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("")
-            .to_string();
+        let upstream = ctx.upstream.as_ref()
+            .ok_or_else(|| GatewayError::Internal("No upstream selected".to_string()))?;
+        let path = match ctx.convert_to {
+            Some(format) => format.chat_path(),
+            None => upstream_request.uri.path(),
+        };
+        let path_and_query = upstream.path_and_query(path, upstream_request.uri.query());
+        let uri = path_and_query.parse::<Uri>()
+            .map_err(|e| GatewayError::Internal(format!("Invalid upstream path {}: {}", path_and_query, e)))?;
+        upstream_request.set_uri(uri);
+        upstream_request.insert_header("Host", upstream.authority())?;
+        if ctx.endpoint.counts_tokens() {
+            upstream_request.insert_header("Content-Type", "application/json")?;
+            // Responses are parsed for usage, so they must not be compressed
+            upstream_request.remove_header("Accept-Encoding");
+        }
+        if self.rewrites_body(ctx) {
+            // The rewritten body's length is only known once it has been read
+            upstream_request.remove_header("Content-Length");
+            upstream_request.insert_header("Transfer-Encoding", "chunked")?;
+        }
+        if ctx.convert_to == Some(ApiFormat::Anthropic) && upstream_request.headers.get("anthropic-version").is_none() {
+            upstream_request.insert_header("anthropic-version", ANTHROPIC_VERSION)?;
+        }
 
-        self.check_rate_limit(&ctx.user).await?;
+        if let Some(auth) = &upstream.auth {
+            // Client credentials are meant for the gateway, not the provider
+            for header in ["Authorization", "x-api-key", "api-key"] {
+                upstream_request.remove_header(header);
+            }
+            let (name, value) = auth.header();
+            upstream_request.insert_header(name.to_string(), value)?;
+        }
         Ok(())
     }
 
     async fn response_filter(
         &self,
-        _: &mut Session,
+        session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> pingora_error::Result<()> {
-        if upstream_response.status.as_u16() != 200 {
-            return Err(Error::explain(
-                HTTPStatus(upstream_response.status.as_u16()),
-                "Upstream error",
-            ));
+        let status = upstream_response.status.as_u16();
+        if let Some(target) = ctx.targets.get(ctx.target) {
+            if let Some(started) = ctx.attempt_started.take() {
+                target.pool.record_latency(ctx.member, started.elapsed());
+            }
+            target.pool.update_quota(ctx.member, &upstream_response.headers);
+            if status == 429 {
+                target.pool.exhaust(ctx.member, retry_after(upstream_response));
+            }
         }
+        self.record_attempt(ctx, UpstreamHealth::failed_status(status));
+        if !upstream_response.status.is_success() {
+            let upstream = ctx.upstream.as_ref().map_or("", |upstream| upstream.name.as_str());
+            self.metrics.record_upstream_error(upstream, status);
+            if RetryPolicy::retryable_status(status) && self.can_retry(session, ctx) {
+                ctx.retry_after = retry_after(upstream_response);
+                let mut e: Box<Error> = GatewayError::Upstream(status, "Upstream error".to_string()).into();
+                e.esource = ErrorSource::Upstream;
+                return Err(e);
+            }
+            ctx.response_started = true;
+            ctx.upstream_error = Some(status);
+            if ctx.convert_to.is_some() {
+                upstream_response.remove_header("Content-Length");
+                upstream_response.insert_header("Transfer-Encoding", "chunked")?;
+            }
+            return Ok(());
+        }
+
+        if self.rate_config.enabled {
+            // Refresh so that the headers include this request's reservation
+            let mut states = Vec::new();
+            for limit in &ctx.limits {
+                if limit.unit == LimitUnit::Concurrency {
+                    continue;
+                }
+                if let Ok(state) = self.fetch_window_state(limit, &ctx.user).await {
+                    states.push((limit.clone(), state));
+                }
+            }
+            self.insert_rate_limit_headers(upstream_response, &states)?;
+        }
+
+        let streaming = ctx.openai_request.as_ref().is_some_and(|req| matches!(req.request_type, RequestType::Stream));
+        if ctx.endpoint == Endpoint::Chat && streaming {
+            let upstream_format = ctx.upstream.as_ref().map_or(ApiFormat::OpenAI, |upstream| upstream.format);
+            let prompt_tokens = ctx.openai_request.as_ref().map_or(0, |req| req.prompt_tokens);
+            ctx.stream_converter = StreamConverter::new(upstream_format, ctx.inbound_format, prompt_tokens);
+            ctx.usage_stream = StreamConverter::new(upstream_format, ApiFormat::OpenAI, prompt_tokens);
+            if ctx.stream_converter.is_some() {
+                upstream_response.remove_header("Content-Length");
+            }
+        } else if ctx.convert_to.is_some() && ctx.openai_request.is_some() {
+            ctx.convert_response = true;
+            // The converted body's length is only known once it has been read
+            upstream_response.remove_header("Content-Length");
+            upstream_response.insert_header("Transfer-Encoding", "chunked")?;
+        }
+        ctx.response_started = true;
         Ok(())
     }
 
@@ -436,63 +1389,135 @@ impl<R: SlidingWindowRateLimiter + Send + Sync> ProxyHttp for HttpGateway<R> {
         end_of_stream: bool,
         ctx: &mut Self::CTX,
     ) -> pingora_error::Result<Option<Duration>> {
-        if let Some(b) = body {
-            ctx.resp_buffer.extend_from_slice(b);
-            println!("Response Body: {:#?}", String::from_utf8_lossy(b).to_string());
-            // let anthropic_converter = ConverterFactory::get_converter("anthropic").unwrap();
-        
-            // anthropic_converter.convert_response(data, source_format, target_format)
-            // let parser = OpenAIStreamParser::new();
-            // let line = r#"data: {"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#;
-            
-            // let chunk = parser.parse_line(&String::from_utf8_lossy(b).to_string()).unwrap();
-            // println!("Parsed chunk: {:#?}", chunk);
-            // // let data = serde_json::to_value(&chunk).unwrap();
-            // let anthropic_converter = AnthropicConverter::new();
-            // let _ = anthropic_converter.set_original_model(chunk.model.unwrap().as_str());
-            // let res = anthropic_converter.convert_from_openai_streaming_chunk(data);
-            // // anthropic_converter.parse_anthropic_sse_event(sse_data)
-            
-            // println!("Response Conversion result: {:#?}", res);
+        // Error bodies carry no usage; they are only translated for the client
+        if let Some(status) = ctx.upstream_error {
+            if let Some(format) = ctx.convert_to {
+                if let Some(b) = body.take() {
+                    ctx.resp_buffer.extend_from_slice(&b);
+                }
+                if end_of_stream {
+                    let converted = convert_error(&ctx.resp_buffer, status, format, ctx.inbound_format);
+                    *body = Some(Bytes::from(converted));
+                }
+            }
+            return Ok(None);
         }
 
+        let chunk = body.take();
+        // Tokens are counted on the OpenAI form of the stream
+        match &mut ctx.usage_stream {
+            Some(converter) => ctx.resp_buffer.extend(convert_chunk(converter, chunk.as_ref(), end_of_stream)),
+            None => ctx.resp_buffer.extend_from_slice(chunk.as_deref().unwrap_or_default()),
+        }
+        *body = match &mut ctx.stream_converter {
+            Some(converter) => {
+                Some(Bytes::from(convert_chunk(converter, chunk.as_ref(), end_of_stream))).filter(|b| !b.is_empty())
+            },
+            None if ctx.convert_response => match (end_of_stream, ctx.convert_to) {
+                (true, Some(format)) => {
+                    let converted = convert_response(&ctx.resp_buffer, format, ctx.inbound_format)
+                        .map_err(|e| GatewayError::ResponseConversion(format!("Invalid response: {}", e)))?;
+                    Some(Bytes::from(converted))
+                },
+                _ => None,
+            },
+            None => chunk,
+        };
 
         if end_of_stream {
-            if let Some(req) = &ctx.openai_request {
-                let usage = match req.request_type {
-                    RequestType::Stream => {
-                        let completion_tokens = self.parse_streaming_response(&ctx.resp_buffer)?;
-                        TokenUsage {
-                            prompt_tokens: req.prompt_tokens,
-                            completion_tokens,
-                        }
-                    },
-                    RequestType::NonStream => {
-                        let response: UsageResponse = from_slice(&ctx.resp_buffer)
-                            .map_err(|_| Error::explain(HTTPStatus(502), "Invalid response"))?;
-                        TokenUsage {
-                            prompt_tokens: response.usage.prompt_tokens,
-                            completion_tokens: response.usage.completion_tokens,
-                        }
-                    },
-                };
-                println!("Usage: {:?}", usage);
-                // Update metrics and rate limiter
-                self.metrics.record(&usage, &req.model, &ctx.user);
-                let total_tokens = usage.prompt_tokens + usage.completion_tokens;
-                let _ = self.rate_limiter.record_sliding_window(
-                    USER_RESOURCE,
-                    &ctx.user,
-                    total_tokens,
-                    Duration::from_secs(self.rate_config.window_duration_min * 60),
-                );
-            }
+            self.count_usage(ctx)?;
         }
 
         Ok(None)
     }
 
-    async fn logging(&self, session: &mut Session, _: Option<&Error>, _: &mut Self::CTX) {
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let upstream = ctx.upstream.as_ref().map_or("", |upstream| upstream.name.as_str());
+        warn!("Failed to connect to upstream {}: {}", upstream, e);
+        self.record_attempt(ctx, true);
+        e.set_retry(self.retry(session, ctx));
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        _: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        // Failures of the client are not retried
+        let upstream_failed = matches!(e.esource(), ErrorSource::Upstream);
+        if upstream_failed {
+            self.record_attempt(ctx, true);
+        }
+        let retry = upstream_failed && self.retry(session, ctx);
+        e.set_retry(retry);
+        e
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> FailToProxy {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        if code > 0 {
+            let error = e.cause.as_deref()
+                .and_then(|cause| cause.downcast_ref::<GatewayError>())
+                .cloned()
+                .unwrap_or_else(|| GatewayError::from_status(code));
+            let body = error.body(ctx.inbound_format);
+            let response = match &ctx.rate_limited {
+                Some((limit, state)) if code == 429 => self.rate_limited_response(limit, state, &body),
+                _ => Self::error_response(code, &body),
+            };
+            let result = match response {
+                Ok(response) => match session.write_response_header(Box::new(response), false).await {
+                    Ok(()) => session.write_response_body(Some(Bytes::from(body)), true).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Failed to send {} response: {}", code, e);
+            }
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
+    async fn logging(&self, session: &mut Session, _: Option<&Error>, ctx: &mut Self::CTX) {
+        let usage = ctx.usage.take();
+        if let (Some(req), Some(usage)) = (&ctx.openai_request, &usage) {
+            self.metrics.record(usage, &req.model, &req.requested_model, &ctx.user);
+            self.metrics.record_prompt_estimate(&req.model, req.prompt_tokens, ctx.reported_prompt_tokens);
+        }
+        self.settle_reservations(ctx, usage.as_ref()).await;
+        Self::finish_attempt(ctx);
+        if let (true, Some(upstream)) = (ctx.attempt_pending, &ctx.upstream) {
+            // Ended before the upstream answered
+            self.health.release(upstream);
+        }
+
         let status = session.response_written()
             .map_or(0, |resp| resp.status.as_u16());
         info!("{} {} - {}", 
@@ -501,4 +1526,360 @@ impl<R: SlidingWindowRateLimiter + Send + Sync> ProxyHttp for HttpGateway<R> {
             status
         );
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use http::{HeaderMap, HeaderValue};
+    use pingora::prelude::ProxyHttp;
+
+    use super::{
+        format_reset, prompt_drift, valid_user, AnonymousPolicy, Ctx, Endpoint, GatewayMetrics,
+        HttpGateway, HttpGatewayConfig, OpenAIRequest, RateLimit, RateLimitingConfig, RequestType,
+        TokenUsage,
+    };
+    use crate::alias::ModelAliases;
+    use crate::balancer::{Member, Pool, Strategy};
+    use crate::health::{HealthConfig, UpstreamHealth};
+    use crate::policy::RateLimitPolicies;
+    use crate::rate_limiter::{
+        InMemorySlidingWindowRateLimiter, Reservation, SlidingWindowRateLimiter, WindowState,
+    };
+    use crate::retry::RetryPolicy;
+    use crate::tokenizer::{Encoding, TokenizerRegistry};
+    use crate::upstream::{RoutingTable, Target, Upstream};
+
+    fn gateway<R>(rate_limiter: R, limits: Vec<RateLimit>) -> HttpGateway<R>
+    where
+        R: SlidingWindowRateLimiter + Send + Sync,
+    {
+        let upstream = Upstream::new("openai", "api.openai.com", 443, true);
+        let routing_table = RoutingTable::single(upstream);
+        let health = UpstreamHealth::new(&routing_table, HealthConfig {
+            interval_secs: 30,
+            health_check_path: "/v1/models".to_string(),
+            timeout_ms: 5000,
+            unhealthy_threshold: 2,
+            window: 20,
+            min_requests: 10,
+            failure_rate: 0.5,
+            cooldown_secs: 30,
+        });
+        HttpGateway::new(HttpGatewayConfig {
+            routing_table,
+            aliases: ModelAliases::default(),
+            tokenizers: TokenizerRegistry::new(Encoding::Cl100kBase),
+            sliding_window_rate_limiter: rate_limiter,
+            rate_limiting_config: RateLimitingConfig {
+                enabled: true,
+                limits,
+                policies: RateLimitPolicies::default(),
+                user_header_key: "user",
+                anonymous_policy: AnonymousPolicy::Anonymous,
+                default_completion_tokens: 100,
+            },
+            retry_policy: RetryPolicy { max_retries: 2, backoff_ms: 200, max_backoff_ms: 5000 },
+            health: Arc::new(health),
+        })
+        .unwrap()
+    }
+
+    fn new_ctx<R>(gateway: &HttpGateway<R>, user: &str) -> Ctx
+    where
+        R: SlidingWindowRateLimiter + Send + Sync,
+    {
+        let mut ctx = gateway.new_ctx();
+        ctx.user = user.to_string();
+        ctx.endpoint = Endpoint::Chat;
+        ctx
+    }
+
+    fn chat_request(prompt_tokens: u64, max_tokens: Option<u64>) -> OpenAIRequest {
+        OpenAIRequest {
+            model: "gpt-4o".to_string(),
+            requested_model: "gpt-4o".to_string(),
+            endpoint: Endpoint::Chat,
+            request_type: RequestType::NonStream,
+            prompt_tokens,
+            max_tokens,
+        }
+    }
+
+    async fn window_total<R>(gateway: &HttpGateway<R>, limit: usize, user: &str) -> u64
+    where
+        R: SlidingWindowRateLimiter + Send + Sync,
+    {
+        let limit = &gateway.rate_config.limits[limit];
+        gateway.fetch_window_state(limit, user).await.unwrap().total
+    }
+
+    /// Admits every request but cannot record what they used.
+    struct FailingRateLimiter(InMemorySlidingWindowRateLimiter);
+
+    #[async_trait]
+    impl SlidingWindowRateLimiter for FailingRateLimiter {
+        async fn record_sliding_window(
+            &self,
+            resource: &str,
+            subject: &str,
+            tokens: u64,
+            size: Duration,
+        ) -> Result<u64> {
+            self.0.record_sliding_window(resource, subject, tokens, size).await
+        }
+
+        async fn fetch_sliding_window(
+            &self,
+            resource: &str,
+            subject: &str,
+            size: Duration,
+        ) -> Result<u64> {
+            self.0.fetch_sliding_window(resource, subject, size).await
+        }
+
+        async fn fetch_sliding_window_state(
+            &self,
+            resource: &str,
+            subject: &str,
+            size: Duration,
+        ) -> Result<WindowState> {
+            self.0.fetch_sliding_window_state(resource, subject, size).await
+        }
+
+        async fn reserve_sliding_window(
+            &self,
+            resource: &str,
+            subject: &str,
+            tokens: u64,
+            size: Duration,
+        ) -> Result<Reservation> {
+            self.0.reserve_sliding_window(resource, subject, tokens, size).await
+        }
+
+        async fn commit_sliding_window(
+            &self,
+            _resource: &str,
+            _subject: &str,
+            _reservation: &Reservation,
+            _tokens: u64,
+            _size: Duration,
+        ) -> Result<u64> {
+            Err(anyhow!("backend unavailable"))
+        }
+
+        async fn release_sliding_window(
+            &self,
+            resource: &str,
+            subject: &str,
+            reservation: &Reservation,
+            size: Duration,
+        ) -> Result<u64> {
+            self.0.release_sliding_window(resource, subject, reservation, size).await
+        }
+    }
+
+    #[test]
+    fn test_format_reset() {
+        assert_eq!(format_reset(Duration::from_millis(0)), "0ms");
+        assert_eq!(format_reset(Duration::from_millis(20)), "20ms");
+        assert_eq!(format_reset(Duration::from_millis(1_500)), "1.5s");
+        assert_eq!(format_reset(Duration::from_secs(360)), "6m0s");
+        assert_eq!(format_reset(Duration::from_millis(3_725_250)), "1h2m5.25s");
+    }
+
+    #[test]
+    fn test_prompt_drift() {
+        assert_eq!(prompt_drift(110, 100), Some(0.1));
+        assert_eq!(prompt_drift(95, 100), Some(-0.05));
+        assert_eq!(prompt_drift(0, 100), Some(-1.0));
+        assert_eq!(prompt_drift(12, 0), None);
+    }
+
+    #[test]
+    fn test_endpoint_from_path() {
+        assert_eq!(Endpoint::from_path("/v1/chat/completions"), Endpoint::Chat);
+        assert_eq!(Endpoint::from_path("/v1/messages"), Endpoint::Chat);
+        assert_eq!(Endpoint::from_path("/v1/completions"), Endpoint::Completions);
+        assert_eq!(Endpoint::from_path("/v1/embeddings/"), Endpoint::Embeddings);
+        assert_eq!(Endpoint::from_path("/v1/models"), Endpoint::Other);
+        assert_eq!(Endpoint::from_path("/v1/audio/transcriptions"), Endpoint::Other);
+
+        assert!(Endpoint::Chat.has_completion());
+        assert!(!Endpoint::Embeddings.has_completion());
+    }
+
+    #[test]
+    fn test_anonymous_policy() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)));
+        let key = Some("sk-test");
+
+        assert_eq!(AnonymousPolicy::Reject.subject(ip, key), None);
+        assert_eq!(AnonymousPolicy::ClientIp.subject(ip, key).unwrap(), "ip:10.0.0.7");
+        assert_eq!(AnonymousPolicy::ClientIp.subject(None, key).unwrap(), "anonymous");
+        let hashed = AnonymousPolicy::ApiKey.subject(ip, key).unwrap();
+        assert!(hashed.starts_with("key:") && hashed.len() == 20);
+        assert_ne!(AnonymousPolicy::ApiKey.subject(ip, Some("sk-other")).unwrap(), hashed);
+        assert_eq!(AnonymousPolicy::ApiKey.subject(ip, None).unwrap(), "anonymous");
+        assert_eq!(AnonymousPolicy::Anonymous.subject(ip, key).unwrap(), "anonymous");
+
+        assert!(valid_user("alice@example.com"));
+        assert!(!valid_user(""));
+        assert!(!valid_user("bad user"));
+        assert!(!valid_user(&"a".repeat(300)));
+        assert!(!valid_user("anonymous"));
+        assert!(!valid_user("ip:10.0.0.7"));
+        assert!(!valid_user(&hashed));
+        assert!(valid_user("ipv6-team"));
+    }
+
+    #[tokio::test]
+    async fn test_usage_replaces_reservation() {
+        let limits = vec![RateLimit::tokens("tokens", 1000, Duration::from_secs(60))];
+        let gateway = gateway(InMemorySlidingWindowRateLimiter::new(), limits);
+
+        let mut ctx = new_ctx(&gateway, "alice");
+        gateway.check_rate_limit(&mut ctx, &chat_request(100, Some(400))).await.unwrap();
+        assert_eq!(window_total(&gateway, 0, "alice").await, 500);
+        let usage = TokenUsage { prompt_tokens: 100, completion_tokens: 20 };
+        gateway.settle_reservations(&mut ctx, Some(&usage)).await;
+        assert_eq!(window_total(&gateway, 0, "alice").await, 120);
+
+        // Without usage, e.g. a failed request, the reservation is given back
+        let mut ctx = new_ctx(&gateway, "alice");
+        gateway.check_rate_limit(&mut ctx, &chat_request(100, Some(400))).await.unwrap();
+        gateway.settle_reservations(&mut ctx, None).await;
+        assert_eq!(window_total(&gateway, 0, "alice").await, 120);
+    }
+
+    #[test]
+    fn test_response_without_usage() {
+        let gateway = gateway(InMemorySlidingWindowRateLimiter::new(), Vec::new());
+
+        let mut ctx = new_ctx(&gateway, "alice");
+        ctx.openai_request = Some(chat_request(100, None));
+        ctx.resp_buffer = br#"{"id":"chatcmpl-1","choices":[]}"#.to_vec();
+        gateway.count_usage(&mut ctx).unwrap();
+        let usage = ctx.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (100, 0));
+        assert_eq!(ctx.reported_prompt_tokens, None);
+
+        let mut ctx = new_ctx(&gateway, "alice");
+        ctx.openai_request = Some(chat_request(100, None));
+        ctx.resp_buffer = br#"{"usage":{"prompt_tokens":90,"completion_tokens":12}}"#.to_vec();
+        gateway.count_usage(&mut ctx).unwrap();
+        let usage = ctx.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (90, 12));
+        assert_eq!(ctx.reported_prompt_tokens, Some(90));
+    }
+
+    #[tokio::test]
+    async fn test_recording_failure_is_counted() {
+        let limits = vec![RateLimit::tokens("tokens", 1000, Duration::from_secs(60))];
+        let gateway = gateway(FailingRateLimiter(InMemorySlidingWindowRateLimiter::new()), limits);
+        let errors = GatewayMetrics::instance().rate_limiter_errors;
+        let failures = || errors.with_label_values(&["commit"]).get();
+        let before = failures();
+
+        let mut ctx = new_ctx(&gateway, "bob");
+        gateway.check_rate_limit(&mut ctx, &chat_request(100, Some(400))).await.unwrap();
+        let usage = TokenUsage { prompt_tokens: 100, completion_tokens: 20 };
+        gateway.settle_reservations(&mut ctx, Some(&usage)).await;
+        assert_eq!(failures(), before + 1);
+        assert!(ctx.reservations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_limits_are_reserved_together() {
+        let limits = vec![
+            RateLimit::tokens("tokens_per_minute", 1000, Duration::from_secs(60)),
+            RateLimit::requests("requests_per_minute", 10, Duration::from_secs(60)),
+            RateLimit::concurrency("concurrent_requests", 1),
+        ];
+        let gateway = gateway(InMemorySlidingWindowRateLimiter::new(), limits);
+
+        let mut first = new_ctx(&gateway, "carol");
+        gateway.check_rate_limit(&mut first, &chat_request(100, Some(400))).await.unwrap();
+        assert_eq!(first.reservations.len(), 3);
+        assert_eq!(window_total(&gateway, 0, "carol").await, 500);
+        assert_eq!(window_total(&gateway, 1, "carol").await, 1);
+        assert_eq!(window_total(&gateway, 2, "carol").await, 1);
+
+        // Rejected by the concurrency limit, the tokens and request it
+        // reserved against the others are given back
+        let mut second = new_ctx(&gateway, "carol");
+        let rejected = gateway.check_rate_limit(&mut second, &chat_request(100, Some(400))).await;
+        assert!(rejected.is_err());
+        assert_eq!(second.rate_limited.as_ref().unwrap().0.name, "concurrent_requests");
+        assert!(second.reservations.is_empty());
+        assert_eq!(window_total(&gateway, 0, "carol").await, 500);
+        assert_eq!(window_total(&gateway, 1, "carol").await, 1);
+        assert_eq!(window_total(&gateway, 2, "carol").await, 1);
+
+        let usage = TokenUsage { prompt_tokens: 100, completion_tokens: 50 };
+        gateway.settle_reservations(&mut first, Some(&usage)).await;
+        assert_eq!(window_total(&gateway, 2, "carol").await, 0);
+        let mut third = new_ctx(&gateway, "carol");
+        gateway.check_rate_limit(&mut third, &chat_request(100, Some(400))).await.unwrap();
+        assert_eq!(window_total(&gateway, 0, "carol").await, 650);
+        assert_eq!(window_total(&gateway, 1, "carol").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_limit_admits_up_to_its_max() {
+        let limits = vec![
+            RateLimit::tokens("tokens_per_minute", 1000, Duration::from_secs(60)),
+            RateLimit::requests("requests_per_minute", 2, Duration::from_secs(60)),
+        ];
+        let gateway = gateway(InMemorySlidingWindowRateLimiter::new(), limits);
+
+        // Requests are admitted by their headers, tokens once counted
+        for _ in 0..2 {
+            let mut ctx = new_ctx(&gateway, "dave");
+            gateway.check_request_limits(&mut ctx, Some("gpt-4o")).await.unwrap();
+            assert_eq!(ctx.reservations.len(), 1);
+        }
+        let mut ctx = new_ctx(&gateway, "dave");
+        assert!(gateway.check_request_limits(&mut ctx, Some("gpt-4o")).await.is_err());
+        assert_eq!(ctx.rate_limited.as_ref().unwrap().0.name, "requests_per_minute");
+        assert_eq!(window_total(&gateway, 1, "dave").await, 2);
+
+        // A request using up exactly what is left is admitted
+        let mut ctx = new_ctx(&gateway, "erin");
+        gateway.check_rate_limit(&mut ctx, &chat_request(600, Some(400))).await.unwrap();
+        assert_eq!(window_total(&gateway, 0, "erin").await, 1000);
+        let mut ctx = new_ctx(&gateway, "erin");
+        assert!(gateway.check_rate_limit(&mut ctx, &chat_request(0, Some(1))).await.is_err());
+        assert_eq!(ctx.rate_limited.as_ref().unwrap().0.name, "tokens_per_minute");
+        assert_eq!(window_total(&gateway, 0, "erin").await, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_member_without_quota_is_skipped() {
+        let gateway = gateway(InMemorySlidingWindowRateLimiter::new(), Vec::new());
+        let members = (0..2u16)
+            .map(|i| {
+                let upstream = Upstream::new(&format!("member{}", i), "127.0.0.1", 8000 + i, false);
+                Member { upstream: Arc::new(upstream), weight: 1 }
+            })
+            .collect();
+        let pool = Arc::new(Pool::new("pool", Strategy::WeightedRoundRobin, members));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining-tokens", HeaderValue::from_static("100"));
+        pool.update_quota(0, &headers);
+
+        // Counted from the body read ahead, before the first member is picked
+        let mut ctx = new_ctx(&gateway, "frank");
+        ctx.targets = vec![Target { pool, model: None }];
+        let body = r#"{"model": "gpt-4o", "max_tokens": 500, "messages": [{"role": "user", "content": "hi"}]}"#;
+        ctx.req_buffer = body.as_bytes().to_vec();
+        gateway.count_request(&mut ctx).await.unwrap();
+        gateway.select_member(&mut ctx).unwrap();
+        assert_eq!(ctx.member, 1);
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::http_proxy::RateLimit;
+use crate::rate_limiter::{Reservation, WindowState, IDLE_SWEEP_INTERVAL_MS};
+
+/// Source of the current time in milliseconds since the epoch.
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+impl<C: Clock> Clock for Arc<C> {
+    fn now_millis(&self) -> u64 {
+        self.as_ref().now_millis()
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlgorithmKind {
+    SlidingWindow,
+    FixedWindow,
+    TokenBucket,
+    Gcra,
+}
+
+/// How a limit is enforced. Sliding windows go through the configured rate
+/// limiter backend; the other algorithms keep their state in the gateway
+/// process.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Algorithm {
+    #[default]
+    SlidingWindow,
+    /// Counter reset at every window boundary
+    FixedWindow,
+    /// Refills `max` units per window up to `burst` (defaults to `max`)
+    TokenBucket { burst: Option<u64> },
+    /// Generic cell rate algorithm, spacing units evenly over the window
+    /// while tolerating bursts of up to `burst` (defaults to `max`)
+    Gcra { burst: Option<u64> },
+}
+
+impl Algorithm {
+    pub fn new(kind: AlgorithmKind, burst: Option<u64>) -> Self {
+        match kind {
+            AlgorithmKind::SlidingWindow => Algorithm::SlidingWindow,
+            AlgorithmKind::FixedWindow => Algorithm::FixedWindow,
+            AlgorithmKind::TokenBucket => Algorithm::TokenBucket { burst },
+            AlgorithmKind::Gcra => Algorithm::Gcra { burst },
+        }
+    }
+}
+
+/// Rounds a fractional usage up, ignoring floating point noise.
+fn ceil_units(units: f64) -> u64 {
+    (units - 1e-9).ceil().max(0.0) as u64
+}
+
+fn millis(ms: f64) -> Duration {
+    Duration::from_millis(ms.max(0.0).ceil() as u64)
+}
+
+struct FixedWindow {
+    start: u64,
+    count: u64,
+}
+
+impl FixedWindow {
+    fn roll(&mut self, now: u64, limit: &RateLimit) {
+        let window_ms = (limit.window.as_millis() as u64).max(1);
+        let start = now - now % window_ms;
+        if start != self.start {
+            self.start = start;
+            self.count = 0;
+        }
+    }
+
+    fn reserve(&mut self, now: u64, limit: &RateLimit, tokens: u64) -> Reservation {
+        self.roll(now, limit);
+        self.count += tokens;
+        Reservation {
+            bucket: self.start,
+            tokens,
+            total: self.count,
+        }
+    }
+
+    fn release(&mut self, now: u64, limit: &RateLimit, reservation: &Reservation) -> u64 {
+        self.roll(now, limit);
+        if reservation.bucket == self.start {
+            self.count -= reservation.tokens.min(self.count);
+        }
+        self.count
+    }
+
+    fn state(&mut self, now: u64, limit: &RateLimit) -> WindowState {
+        self.roll(now, limit);
+        let reset = if self.count > 0 {
+            Duration::from_millis(self.start + limit.window.as_millis() as u64 - now)
+        } else {
+            Duration::ZERO
+        };
+        WindowState {
+            total: self.count,
+            retry_after: reset,
+            reset,
+        }
+    }
+}
+
+struct TokenBucket {
+    /// Units currently available; negative when usage overran a reservation
+    level: f64,
+    updated: u64,
+}
+
+impl TokenBucket {
+    fn new(now: u64, limit: &RateLimit) -> Self {
+        Self {
+            level: limit.capacity() as f64,
+            updated: now,
+        }
+    }
+
+    /// Units refilled per millisecond
+    fn rate(limit: &RateLimit) -> f64 {
+        limit.max as f64 / (limit.window.as_millis() as f64).max(1.0)
+    }
+
+    fn refill(&mut self, now: u64, limit: &RateLimit) {
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        self.level = (self.level + elapsed * Self::rate(limit)).min(limit.capacity() as f64);
+        self.updated = now.max(self.updated);
+    }
+
+    fn used(&self, limit: &RateLimit) -> u64 {
+        ceil_units(limit.capacity() as f64 - self.level)
+    }
+
+    fn adjust(&mut self, now: u64, limit: &RateLimit, refund: u64, take: u64) -> u64 {
+        self.refill(now, limit);
+        self.level = (self.level + refund as f64).min(limit.capacity() as f64) - take as f64;
+        self.used(limit)
+    }
+
+    fn state(&mut self, now: u64, limit: &RateLimit) -> WindowState {
+        self.refill(now, limit);
+        let rate = Self::rate(limit);
+        WindowState {
+            total: self.used(limit),
+            retry_after: millis((1.0 - self.level) / rate),
+            reset: millis((limit.capacity() as f64 - self.level) / rate),
+        }
+    }
+}
+
+struct Gcra {
+    /// Theoretical arrival time (ms) of the next unit
+    tat: f64,
+}
+
+impl Gcra {
+    /// Milliseconds each unit occupies
+    fn emission_interval(limit: &RateLimit) -> f64 {
+        (limit.window.as_millis() as f64).max(1.0) / limit.max.max(1) as f64
+    }
+
+    fn used(&self, now: u64, limit: &RateLimit) -> u64 {
+        ceil_units((self.tat - now as f64).max(0.0) / Self::emission_interval(limit))
+    }
+
+    fn adjust(&mut self, now: u64, limit: &RateLimit, refund: u64, take: u64) -> u64 {
+        let interval = Self::emission_interval(limit);
+        let now_ms = now as f64;
+        self.tat = (self.tat.max(now_ms) - refund as f64 * interval).max(now_ms)
+            + take as f64 * interval;
+        self.used(now, limit)
+    }
+
+    fn state(&mut self, now: u64, limit: &RateLimit) -> WindowState {
+        let interval = Self::emission_interval(limit);
+        let ahead = (self.tat - now as f64).max(0.0);
+        WindowState {
+            total: self.used(now, limit),
+            retry_after: millis(ahead - (limit.capacity().saturating_sub(1)) as f64 * interval),
+            reset: millis(ahead),
+        }
+    }
+}
+
+enum LimiterState {
+    FixedWindow(FixedWindow),
+    TokenBucket(TokenBucket),
+    Gcra(Gcra),
+}
+
+impl LimiterState {
+    fn new(now: u64, limit: &RateLimit) -> Self {
+        match limit.algorithm {
+            Algorithm::TokenBucket { .. } => LimiterState::TokenBucket(TokenBucket::new(now, limit)),
+            Algorithm::Gcra { .. } => LimiterState::Gcra(Gcra { tat: now as f64 }),
+            Algorithm::FixedWindow | Algorithm::SlidingWindow => {
+                LimiterState::FixedWindow(FixedWindow { start: 0, count: 0 })
+            }
+        }
+    }
+
+    fn reserve(&mut self, now: u64, limit: &RateLimit, tokens: u64) -> Reservation {
+        match self {
+            LimiterState::FixedWindow(window) => window.reserve(now, limit, tokens),
+            LimiterState::TokenBucket(bucket) => Reservation {
+                bucket: now,
+                tokens,
+                total: bucket.adjust(now, limit, 0, tokens),
+            },
+            LimiterState::Gcra(gcra) => Reservation {
+                bucket: now,
+                tokens,
+                total: gcra.adjust(now, limit, 0, tokens),
+            },
+        }
+    }
+
+    fn commit(&mut self, now: u64, limit: &RateLimit, reservation: &Reservation, tokens: u64) -> u64 {
+        match self {
+            LimiterState::FixedWindow(window) => {
+                window.release(now, limit, reservation);
+                window.reserve(now, limit, tokens).total
+            }
+            LimiterState::TokenBucket(bucket) => bucket.adjust(now, limit, reservation.tokens, tokens),
+            LimiterState::Gcra(gcra) => gcra.adjust(now, limit, reservation.tokens, tokens),
+        }
+    }
+
+    fn release(&mut self, now: u64, limit: &RateLimit, reservation: &Reservation) -> u64 {
+        match self {
+            LimiterState::FixedWindow(window) => window.release(now, limit, reservation),
+            LimiterState::TokenBucket(bucket) => bucket.adjust(now, limit, reservation.tokens, 0),
+            LimiterState::Gcra(gcra) => gcra.adjust(now, limit, reservation.tokens, 0),
+        }
+    }
+
+    fn state(&mut self, now: u64, limit: &RateLimit) -> WindowState {
+        match self {
+            LimiterState::FixedWindow(window) => window.state(now, limit),
+            LimiterState::TokenBucket(bucket) => bucket.state(now, limit),
+            LimiterState::Gcra(gcra) => gcra.state(now, limit),
+        }
+    }
+
+    /// Whether the state is back to what a fresh one would report.
+    fn is_idle(&mut self, now: u64, limit: &RateLimit) -> bool {
+        self.state(now, limit).total == 0
+    }
+}
+
+struct LocalState {
+    limiters: HashMap<(String, String), (RateLimit, LimiterState)>,
+    last_sweep: u64,
+}
+
+/// Process-local limiter for the fixed window, token bucket and GCRA
+/// algorithms. It follows the same reserve/commit/release protocol as
+/// `SlidingWindowRateLimiter`, with the reservation total expressed as the
+/// units in use.
+pub struct LocalRateLimiter<C: Clock = SystemClock> {
+    clock: C,
+    state: Mutex<LocalState>,
+}
+
+impl LocalRateLimiter<SystemClock> {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl Default for LocalRateLimiter<SystemClock> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> LocalRateLimiter<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            state: Mutex::new(LocalState {
+                limiters: HashMap::new(),
+                last_sweep: 0,
+            }),
+        }
+    }
+
+    fn with_state<T>(
+        &self,
+        resource: &str,
+        subject: &str,
+        limit: &RateLimit,
+        f: impl FnOnce(&mut LimiterState, u64) -> T,
+    ) -> T {
+        let now = self.clock.now_millis();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_sub(state.last_sweep) >= IDLE_SWEEP_INTERVAL_MS {
+            state.last_sweep = now;
+            state.limiters.retain(|_, (limit, limiter)| !limiter.is_idle(now, limit));
+        }
+
+        let (_, limiter) = state
+            .limiters
+            .entry((resource.to_string(), subject.to_string()))
+            .or_insert_with(|| (limit.clone(), LimiterState::new(now, limit)));
+        f(limiter, now)
+    }
+
+    pub fn reserve(&self, resource: &str, subject: &str, limit: &RateLimit, tokens: u64) -> Reservation {
+        self.with_state(resource, subject, limit, |limiter, now| limiter.reserve(now, limit, tokens))
+    }
+
+    pub fn commit(
+        &self,
+        resource: &str,
+        subject: &str,
+        limit: &RateLimit,
+        reservation: &Reservation,
+        tokens: u64,
+    ) -> u64 {
+        self.with_state(resource, subject, limit, |limiter, now| {
+            limiter.commit(now, limit, reservation, tokens)
+        })
+    }
+
+    pub fn release(&self, resource: &str, subject: &str, limit: &RateLimit, reservation: &Reservation) -> u64 {
+        self.with_state(resource, subject, limit, |limiter, now| limiter.release(now, limit, reservation))
+    }
+
+    pub fn state(&self, resource: &str, subject: &str, limit: &RateLimit) -> WindowState {
+        self.with_state(resource, subject, limit, |limiter, now| limiter.state(now, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Algorithm, Clock, LocalRateLimiter};
+    use crate::http_proxy::RateLimit;
+
+    struct ManualClock(AtomicU64);
+
+    impl ManualClock {
+        fn advance(&self, ms: u64) {
+            self.0.fetch_add(ms, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now_millis(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn limiter(start: u64) -> (Arc<ManualClock>, LocalRateLimiter<Arc<ManualClock>>) {
+        let clock = Arc::new(ManualClock(AtomicU64::new(start)));
+        (clock.clone(), LocalRateLimiter::with_clock(clock))
+    }
+
+    fn limit(max: u64, algorithm: Algorithm) -> RateLimit {
+        let mut limit = RateLimit::tokens("tokens", max, Duration::from_secs(60));
+        limit.algorithm = algorithm;
+        limit
+    }
+
+    #[test]
+    fn test_fixed_window_resets_at_boundary() {
+        let (clock, limiter) = limiter(10_000);
+        let limit = limit(100, Algorithm::FixedWindow);
+
+        assert_eq!(limiter.reserve("user", "u", &limit, 60).total, 60);
+        let second = limiter.reserve("user", "u", &limit, 60);
+        assert_eq!(second.total, 120);
+        assert_eq!(limiter.release("user", "u", &limit, &second), 60);
+        assert_eq!(limiter.state("user", "u", &limit).reset, Duration::from_millis(50_000));
+
+        clock.advance(50_000);
+        assert_eq!(limiter.reserve("user", "u", &limit, 10).total, 10);
+    }
+
+    #[test]
+    fn test_token_bucket_burst_and_refill() {
+        let (clock, limiter) = limiter(0);
+        // 60 units per minute, bursts of up to 10
+        let limit = limit(60, Algorithm::TokenBucket { burst: Some(10) });
+
+        assert_eq!(limiter.reserve("user", "u", &limit, 10).total, 10);
+        let over = limiter.reserve("user", "u", &limit, 1);
+        assert!(over.total > limit.capacity());
+        limiter.release("user", "u", &limit, &over);
+
+        let state = limiter.state("user", "u", &limit);
+        assert_eq!(state.retry_after, Duration::from_secs(1));
+        assert_eq!(state.reset, Duration::from_secs(10));
+
+        clock.advance(3_000);
+        assert_eq!(limiter.state("user", "u", &limit).total, 7);
+        assert_eq!(limiter.reserve("user", "u", &limit, 3).total, 10);
+    }
+
+    #[test]
+    fn test_token_bucket_commit_settles_debt() {
+        let (clock, limiter) = limiter(0);
+        let limit = limit(60, Algorithm::TokenBucket { burst: None });
+
+        let reservation = limiter.reserve("user", "u", &limit, 10);
+        // Actual usage overran the estimate
+        assert_eq!(limiter.commit("user", "u", &limit, &reservation, 70), 70);
+        assert_eq!(limiter.state("user", "u", &limit).retry_after, Duration::from_secs(11));
+
+        clock.advance(60_000);
+        assert_eq!(limiter.state("user", "u", &limit).total, 10);
+    }
+
+    #[test]
+    fn test_gcra_spaces_requests() {
+        let (clock, limiter) = limiter(0);
+        // One unit per second, bursts of up to 3
+        let limit = limit(60, Algorithm::Gcra { burst: Some(3) });
+
+        for expected in 1..=3 {
+            assert_eq!(limiter.reserve("user", "u", &limit, 1).total, expected);
+        }
+        let over = limiter.reserve("user", "u", &limit, 1);
+        assert!(over.total > limit.capacity());
+        limiter.release("user", "u", &limit, &over);
+        assert_eq!(limiter.state("user", "u", &limit).retry_after, Duration::from_secs(1));
+
+        clock.advance(1_000);
+        assert_eq!(limiter.reserve("user", "u", &limit, 1).total, 3);
+        assert_eq!(limiter.state("user", "u", &limit).reset, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_subjects_are_isolated() {
+        let (_, limiter) = limiter(0);
+        let limit = limit(10, Algorithm::Gcra { burst: None });
+
+        limiter.reserve("user", "a", &limit, 10);
+        assert_eq!(limiter.reserve("user", "b", &limit, 1).total, 1);
+    }
+}
@@ -1,15 +1,49 @@
-#![feature(duration_constructors, duration_constructors_lite)]
+use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use pingora::prelude::*;
-use tiktoken_rs::cl100k_base;
+use pingora_core::services::background::background_service;
 
 use http_proxy::{HttpGateway, HttpGatewayConfig};
-use crate::http_proxy::{OpenAIConfig, RateLimitingConfig};
+use crate::alias::ModelAliases;
+use crate::health::{HealthChecker, HealthConfig, UpstreamHealth};
+use crate::http_proxy::{AnonymousPolicy, RateLimit, RateLimitingConfig};
+use crate::limiter::Algorithm;
+use crate::policy::{LimitSettings, RateLimitPolicies};
 use crate::rate_limiter::SlidingWindowRateLimiterEnum;
+use crate::retry::RetryPolicy;
+use crate::tokenizer::{Encoding, TokenizerRegistry};
+use crate::upstream::{RoutingTable, Upstream};
 
+mod alias;
+mod api_format;
+mod balancer;
+mod chat_tokens;
+mod error;
+mod health;
 mod http_proxy;
+mod limiter;
+mod media;
+mod pattern;
+mod policy;
 mod rate_limiter;
+mod redis_async_pool;
+mod response_conversion;
+mod retry;
+mod stream_conversion;
+mod tokenizer;
+mod upstream;
+
+#[derive(ValueEnum, Clone, Debug)]
+enum RateLimiterBackend {
+    /// Per-process windows, for single-node deployments
+    Memory,
+    /// Windows shared by all gateway replicas through Redis
+    Redis,
+    /// Per-node windows persisted in a local SQLite file
+    Sqlite,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -24,6 +58,18 @@ struct Args {
     #[arg(long, help = "OpenAI endpoint domain", default_value = "api.openai.com", env)]
     openai_domain: String,
 
+    #[arg(long, help = "JSON file with upstreams and model/path routes, replaces the OpenAI endpoint options", env)]
+    upstreams: Option<String>,
+
+    #[arg(long, help = "JSON file mapping model aliases to models, optionally `model@upstream`", env)]
+    model_aliases: Option<String>,
+
+    #[command(flatten)]
+    retry: RetryPolicy,
+
+    #[command(flatten)]
+    health: HealthConfig,
+
     // Proxy configuration
     #[arg(long, help = "HTTP proxy port", default_value = "8080", env)]
     proxy_port: String,
@@ -34,70 +80,180 @@ struct Args {
     // Rate limiting configuration  
     #[arg(long, help = "Enable rate limiting", default_value_t = false, env)]
     enable_rate_limiting: bool,
+
+    #[arg(long, help = "Rate limiter backend", value_enum, default_value_t = RateLimiterBackend::Memory, env)]
+    rate_limiter_backend: RateLimiterBackend,
     
     #[arg(long, help = "Redis connection string", default_value = "redis://127.0.0.1:6379/0", env)]
     redis_url: String,
     
     #[arg(long, help = "Redis pool size", default_value_t = 5, env)]
     redis_pool_size: usize,
+
+    #[arg(long, help = "Prefix for rate limiter keys in Redis", default_value = rate_limiter::KEY_PREFIX, env)]
+    redis_key_prefix: String,
+    
+    #[arg(long, help = "SQLite file for the sqlite rate limiter backend", default_value = "rate_limiter.db", env)]
+    sqlite_path: String,
     
     #[arg(long, help = "Rate limit window (minutes)", default_value_t = 60, env)]
     rate_limit_window_min: u64,
     
     #[arg(long, help = "Max tokens per window", default_value_t = 1000, env)]
     max_tokens: u64,
+
+    #[command(flatten)]
+    limits: LimitSettings,
+
+    #[arg(long, help = "JSON file with per user/group and model rate limit policies", env)]
+    rate_limit_policies: Option<String>,
     
     #[arg(long, help = "User header key", default_value = "user", env)]
     user_header: String,
+
+    #[arg(long, help = "How to identify requests without a valid user header", value_enum, default_value_t = AnonymousPolicy::Anonymous, env)]
+    anonymous_policy: AnonymousPolicy,
+
+    #[arg(long, help = "Completion tokens reserved for requests without max_tokens", default_value_t = 1024, env)]
+    default_completion_tokens: u64,
+
+    #[arg(long, help = "JSON file mapping model patterns to tokenizer encodings or vocabulary files", env)]
+    tokenizers: Option<String>,
+
+    #[arg(long, help = "Tokenizer of models matching no pattern and unknown to tiktoken", value_enum, default_value_t = Encoding::Cl100kBase, env)]
+    fallback_tokenizer: Encoding,
 }
 
 impl Args {
-    fn create_openai_config(&self) -> OpenAIConfig {
-        OpenAIConfig {
-            tls: self.openai_tls,
-            port: self.openai_port,
-            domain: self.openai_domain.clone().leak(),
+    fn create_routing_table(&self) -> anyhow::Result<RoutingTable> {
+        match &self.upstreams {
+            Some(path) => RoutingTable::load(path),
+            None => Ok(RoutingTable::single(Upstream::new(
+                "openai",
+                &self.openai_domain,
+                self.openai_port,
+                self.openai_tls,
+            ))),
         }
     }
 
-    fn create_rate_limiting_config(&self) -> RateLimitingConfig {
-        RateLimitingConfig {
-            window_duration_min: self.rate_limit_window_min,
-            max_prompt_tokens: self.max_tokens,
-            user_header_key: self.user_header.clone().leak(),
+    fn create_model_aliases(&self, routing_table: &RoutingTable) -> anyhow::Result<ModelAliases> {
+        match &self.model_aliases {
+            Some(path) => ModelAliases::load(path, routing_table),
+            None => Ok(ModelAliases::default()),
         }
     }
 
-    fn create_rate_limiter(&self) -> SlidingWindowRateLimiterEnum {
-        if self.enable_rate_limiting {
-            // TODO: Create Redis rate limiter when implemented
-            SlidingWindowRateLimiterEnum::Dummy(rate_limiter::DummySlidingWindowRateLimiter {})
-        } else {
-            SlidingWindowRateLimiterEnum::Dummy(rate_limiter::DummySlidingWindowRateLimiter {})
+    fn create_tokenizers(&self) -> anyhow::Result<TokenizerRegistry> {
+        match &self.tokenizers {
+            Some(path) => TokenizerRegistry::load(path, self.fallback_tokenizer),
+            None => Ok(TokenizerRegistry::new(self.fallback_tokenizer)),
         }
     }
+
+    fn create_rate_limits(&self) -> Vec<RateLimit> {
+        let mut limits = vec![RateLimit::tokens(
+            "tokens",
+            self.max_tokens,
+            Duration::from_secs(60 * self.rate_limit_window_min),
+        )
+        .with_algorithm(self.limits.algorithm())];
+        limits.extend(self.limits.to_limits(None));
+        limits
+    }
+
+    fn create_rate_limiting_config(&self) -> anyhow::Result<RateLimitingConfig> {
+        let policies = match &self.rate_limit_policies {
+            Some(path) => RateLimitPolicies::load(path)?,
+            None => RateLimitPolicies::default(),
+        };
+
+        let limits = self.create_rate_limits();
+        // The other algorithms live in the process, they would not be shared
+        // with the other users of the backend
+        let shared = !matches!(self.rate_limiter_backend, RateLimiterBackend::Memory);
+        if self.enable_rate_limiting && shared {
+            let local = limits.iter()
+                .chain(policies.limits())
+                .find(|limit| limit.algorithm != Algorithm::SlidingWindow);
+            if let Some(limit) = local {
+                anyhow::bail!(
+                    "Limit {} uses {:?}, only sliding windows can be stored in the {:?} backend",
+                    limit.name,
+                    limit.algorithm,
+                    self.rate_limiter_backend,
+                );
+            }
+        }
+
+        Ok(RateLimitingConfig {
+            enabled: self.enable_rate_limiting,
+            limits,
+            policies,
+            user_header_key: self.user_header.clone().leak(),
+            anonymous_policy: self.anonymous_policy,
+            default_completion_tokens: self.default_completion_tokens,
+        })
+    }
+
+    fn create_rate_limiter(&self) -> anyhow::Result<SlidingWindowRateLimiterEnum> {
+        if !self.enable_rate_limiting {
+            return Ok(SlidingWindowRateLimiterEnum::Dummy(rate_limiter::DummySlidingWindowRateLimiter {}));
+        }
+        let rate_limiter = match self.rate_limiter_backend {
+            RateLimiterBackend::Memory => SlidingWindowRateLimiterEnum::InMemory(
+                rate_limiter::InMemorySlidingWindowRateLimiter::new(),
+            ),
+            RateLimiterBackend::Redis => SlidingWindowRateLimiterEnum::Redis(
+                rate_limiter::RedisSlidingWindowRateLimiter::new(
+                    &self.redis_url,
+                    self.redis_pool_size,
+                    &self.redis_key_prefix,
+                )
+                .map_err(|e| anyhow::anyhow!("Failed to create Redis rate limiter: {}", e))?,
+            ),
+            RateLimiterBackend::Sqlite => SlidingWindowRateLimiterEnum::Sqlite(
+                rate_limiter::SqliteSlidingWindowRateLimiter::open(&self.sqlite_path)
+                    .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", self.sqlite_path, e))?,
+            ),
+        };
+        Ok(rate_limiter)
+    }
 }
 
-fn create_gateway(args: &Args) -> anyhow::Result<HttpGateway<SlidingWindowRateLimiterEnum>> {
-    let tokenizer = cl100k_base().map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
-    
+fn create_gateway(
+    args: &Args,
+    routing_table: RoutingTable,
+    health: Arc<UpstreamHealth>,
+) -> anyhow::Result<HttpGateway<SlidingWindowRateLimiterEnum>> {
     let config = HttpGatewayConfig {
-        openai_config: args.create_openai_config(),
-        tokenizer,
-        sliding_window_rate_limiter: args.create_rate_limiter(),
-        rate_limiting_config: args.create_rate_limiting_config(),
+        aliases: args.create_model_aliases(&routing_table)?,
+        routing_table,
+        tokenizers: args.create_tokenizers()?,
+        sliding_window_rate_limiter: args.create_rate_limiter()?,
+        rate_limiting_config: args.create_rate_limiting_config()?,
+        retry_policy: args.retry.clone(),
+        health,
     };
 
     HttpGateway::new(config)
 }
 
 fn setup_services(server: &mut Server, args: &Args) -> anyhow::Result<()> {
+    let routing_table = args.create_routing_table()?;
+    let health = Arc::new(UpstreamHealth::new(&routing_table, args.health.clone()));
+
     // Create and configure HTTP proxy service
-    let gateway = create_gateway(args)?;
+    let gateway = create_gateway(args, routing_table, health.clone())?;
     let mut proxy_service = http_proxy_service(&server.configuration, gateway);
     proxy_service.add_tcp(&format!("0.0.0.0:{}", args.proxy_port));
     server.add_service(proxy_service);
 
+    // Probe upstreams in the background
+    if args.health.interval_secs > 0 {
+        server.add_service(background_service("health checks", HealthChecker::new(health)));
+    }
+
     // Create and configure metrics service
     let mut metrics_service = pingora_core::services::listening::Service::prometheus_http_service();
     metrics_service.add_tcp(&format!("0.0.0.0:{}", args.metrics_port));
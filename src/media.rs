@@ -0,0 +1,225 @@
+use base64::{engine::general_purpose, Engine as _};
+
+/// Bitrate assumed for audio whose header cannot be read, in bits per second
+const FALLBACK_AUDIO_BITRATE: f64 = 128_000.0;
+
+/// Decodes the payload of a base64 `data:` URL, e.g.
+/// `data:image/png;base64,iVBORw0...`. Remote URLs give `None`.
+pub fn decode_data_url(url: &str) -> Option<Vec<u8>> {
+    let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+    if !header.ends_with(";base64") {
+        return None;
+    }
+    decode_base64(data)
+}
+
+pub fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    general_purpose::STANDARD.decode(data.trim()).ok()
+}
+
+/// Width and height of a PNG, JPEG, GIF or WebP image, read from its header.
+pub fn image_dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+        // IHDR is always the first chunk
+        return Some((be_u32(image, 16)?, be_u32(image, 20)?));
+    }
+    if image.starts_with(b"GIF87a") || image.starts_with(b"GIF89a") {
+        return Some((le_u16(image, 6)? as u32, le_u16(image, 8)? as u32));
+    }
+    if image.starts_with(&[0xFF, 0xD8]) {
+        return jpeg_dimensions(image);
+    }
+    if image.starts_with(b"RIFF") && image.get(8..12) == Some(b"WEBP") {
+        return webp_dimensions(image);
+    }
+    None
+}
+
+/// Walks the JPEG segments up to the start of frame, which holds the size.
+fn jpeg_dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    let mut offset = 2;
+    loop {
+        if *image.get(offset)? != 0xFF {
+            return None;
+        }
+        let marker = *image.get(offset + 1)?;
+        match marker {
+            // Fill bytes
+            0xFF => offset += 1,
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => offset += 2,
+            // SOF0 to SOF15, except DHT, JPG and DAC
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let height = be_u16(image, offset + 5)? as u32;
+                let width = be_u16(image, offset + 7)? as u32;
+                return Some((width, height));
+            },
+            _ => offset += 2 + be_u16(image, offset + 2)? as usize,
+        }
+    }
+}
+
+fn webp_dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    match image.get(12..16)? {
+        // Extended format, 24-bit sizes minus one
+        b"VP8X" => Some((le_u24(image, 24)? + 1, le_u24(image, 27)? + 1)),
+        // Lossless, 14-bit sizes minus one after the signature byte
+        b"VP8L" => {
+            let bits = le_u32(image, 21)?;
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        },
+        // Lossy, 14-bit sizes after the frame tag and start code
+        b"VP8 " => Some((
+            (le_u16(image, 26)? & 0x3FFF) as u32,
+            (le_u16(image, 28)? & 0x3FFF) as u32,
+        )),
+        _ => None,
+    }
+}
+
+/// Duration in seconds of `wav` or `mp3` audio. Audio whose header cannot
+/// be read is assumed to be 128 kbit/s.
+pub fn audio_duration(audio: &[u8], format: &str) -> f64 {
+    let duration = match format {
+        "wav" => wav_duration(audio),
+        "mp3" => mp3_duration(audio),
+        _ => None,
+    };
+    duration.unwrap_or(audio.len() as f64 * 8.0 / FALLBACK_AUDIO_BITRATE)
+}
+
+/// Size of the `data` chunk over the byte rate of the `fmt ` chunk.
+fn wav_duration(audio: &[u8]) -> Option<f64> {
+    if !audio.starts_with(b"RIFF") || audio.get(8..12) != Some(b"WAVE") {
+        return None;
+    }
+    let mut offset = 12;
+    let mut byte_rate = None;
+    loop {
+        let id = audio.get(offset..offset + 4)?;
+        let size = le_u32(audio, offset + 4)? as usize;
+        match id {
+            b"fmt " => byte_rate = Some(le_u32(audio, offset + 16)?),
+            // Streamed files may leave the size of the data unset
+            b"data" => {
+                let size = size.min(audio.len() - offset - 8);
+                return byte_rate.filter(|&rate| rate > 0).map(|rate| size as f64 / rate as f64);
+            },
+            _ => {},
+        }
+        // Chunks are padded to an even size
+        offset += 8 + size + size % 2;
+    }
+}
+
+/// Assumes a constant bitrate, the one of the first frame.
+fn mp3_duration(audio: &[u8]) -> Option<f64> {
+    let mut offset = 0;
+    if audio.starts_with(b"ID3") {
+        // The tag size is a 28-bit integer of four 7-bit bytes
+        let size = audio.get(6..10)?.iter().fold(0, |size, &b| (size << 7) | (b & 0x7F) as usize);
+        offset = 10 + size;
+    }
+    let header = be_u32(audio, offset)?;
+    if header >> 21 != 0x7FF {
+        return None;
+    }
+    let mpeg1 = (header >> 19) & 0b11 == 0b11;
+    let layer3 = (header >> 17) & 0b11 == 0b01;
+    let index = ((header >> 12) & 0xF) as usize;
+    let kbps = match (mpeg1, layer3) {
+        (true, true) => [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0][index],
+        (false, true) => [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0][index],
+        _ => return None,
+    };
+    (kbps > 0).then(|| (audio.len() - offset) as f64 * 8.0 / (kbps as f64 * 1000.0))
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le_u24(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 3)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{audio_duration, decode_data_url, image_dimensions};
+
+    #[test]
+    fn test_image_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(1024u32.to_be_bytes());
+        png.extend(768u32.to_be_bytes());
+        assert_eq!(image_dimensions(&png), Some((1024, 768)));
+
+        let gif = [b"GIF89a".as_slice(), &640u16.to_le_bytes(), &480u16.to_le_bytes()].concat();
+        assert_eq!(image_dimensions(&gif), Some((640, 480)));
+
+        // SOI, an APP0 segment of 4 bytes, then SOF0 with 8-bit samples
+        let jpeg = [
+            [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00].as_slice(),
+            &[0xFF, 0xC0, 0x00, 0x11, 0x08],
+            &600u16.to_be_bytes(),
+            &800u16.to_be_bytes(),
+        ]
+        .concat();
+        assert_eq!(image_dimensions(&jpeg), Some((800, 600)));
+
+        let webp = [
+            b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0".as_slice(),
+            &[0; 4],
+            &[0xFF, 0x07, 0x00],
+            &[0x37, 0x04, 0x00],
+        ]
+        .concat();
+        assert_eq!(image_dimensions(&webp), Some((2048, 1080)));
+
+        assert_eq!(image_dimensions(b"not an image"), None);
+        assert_eq!(image_dimensions(b"\x89PNG\r\n\x1a\n"), None);
+    }
+
+    #[test]
+    fn test_decode_data_url() {
+        assert_eq!(decode_data_url("data:image/png;base64,aGVsbG8="), Some(b"hello".to_vec()));
+        assert_eq!(decode_data_url("data:text/plain,hello"), None);
+        assert_eq!(decode_data_url("https://example.com/cat.png"), None);
+    }
+
+    #[test]
+    fn test_audio_duration() {
+        // Two seconds of 16 kHz 16-bit mono
+        let wav = [
+            b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".as_slice(),
+            &16_000u32.to_le_bytes(),
+            &32_000u32.to_le_bytes(),
+            &[0x02, 0x00, 0x10, 0x00],
+            b"data",
+            &64_000u32.to_le_bytes(),
+            &[0; 64_000],
+        ]
+        .concat();
+        assert_eq!(audio_duration(&wav, "wav"), 2.0);
+
+        // MPEG-1 layer III at 64 kbit/s
+        let mut mp3 = vec![0xFF, 0xFB, 0x50, 0x00];
+        mp3.resize(24_000, 0);
+        assert_eq!(audio_duration(&mp3, "mp3"), 3.0);
+
+        assert_eq!(audio_duration(&[0; 16_000], "flac"), 1.0);
+    }
+}
@@ -0,0 +1,41 @@
+/// Matches `value` against a glob-style pattern where `*` stands for any
+/// run of characters, e.g. `gpt-4o*` or `*-mini`.
+pub fn matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = value.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("gpt-4o", "gpt-4o"));
+        assert!(!matches("gpt-4o", "gpt-4o-mini"));
+        assert!(matches("gpt-4o*", "gpt-4o-mini"));
+        assert!(matches("gpt-4o*", "gpt-4o"));
+        assert!(!matches("gpt-4o*", "gpt-4"));
+        assert!(matches("*-mini", "gpt-4o-mini"));
+        assert!(matches("claude-*-sonnet*", "claude-3-5-sonnet-20241022"));
+        assert!(!matches("claude-*-sonnet*", "claude-3-opus"));
+        assert!(matches("a*a", "aa"));
+        assert!(!matches("ab*ba", "aba"));
+        assert!(matches("*", ""));
+    }
+}
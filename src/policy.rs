@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::http_proxy::RateLimit;
+use crate::limiter::{Algorithm, AlgorithmKind};
+use crate::pattern;
+
+/// Limits that can be set from the command line or per policy.
+#[derive(clap::Args, Deserialize, Clone, Debug, Default)]
+pub struct LimitSettings {
+    #[arg(long, help = "Max tokens per user per minute", env)]
+    #[serde(default)]
+    pub tokens_per_minute: Option<u64>,
+
+    #[arg(long, help = "Max tokens per user per day", env)]
+    #[serde(default)]
+    pub tokens_per_day: Option<u64>,
+
+    #[arg(long, help = "Max requests per user per minute", env)]
+    #[serde(default)]
+    pub requests_per_minute: Option<u64>,
+
+    #[arg(long, help = "Max in-flight requests per user", env)]
+    #[serde(default)]
+    pub max_concurrent_requests: Option<u64>,
+
+    #[arg(long = "rate-limit-algorithm", value_enum, help = "Algorithm enforcing token and request limits [default: sliding-window]", env = "RATE_LIMIT_ALGORITHM")]
+    #[serde(default)]
+    pub algorithm: Option<AlgorithmKind>,
+
+    #[arg(long = "rate-limit-burst", help = "Burst size for token-bucket and gcra limits, defaults to the limit", env = "RATE_LIMIT_BURST")]
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+impl LimitSettings {
+    pub fn algorithm(&self) -> Algorithm {
+        Algorithm::new(self.algorithm.unwrap_or(AlgorithmKind::SlidingWindow), self.burst)
+    }
+
+    /// Builds the limits, prefixing their names with `scope` when given so
+    /// that each policy keeps its own windows.
+    pub fn to_limits(&self, scope: Option<&str>) -> Vec<RateLimit> {
+        let name = |limit: &str| match scope {
+            Some(scope) => format!("{}:{}", scope, limit),
+            None => limit.to_string(),
+        };
+
+        let algorithm = self.algorithm();
+        let mut limits = Vec::new();
+        if let Some(max) = self.tokens_per_minute {
+            limits.push(RateLimit::tokens(&name("tokens_per_minute"), max, Duration::from_secs(60)));
+        }
+        if let Some(max) = self.tokens_per_day {
+            limits.push(RateLimit::tokens(&name("tokens_per_day"), max, Duration::from_secs(24 * 60 * 60)));
+        }
+        if let Some(max) = self.requests_per_minute {
+            limits.push(RateLimit::requests(&name("requests_per_minute"), max, Duration::from_secs(60)));
+        }
+        if let Some(max) = self.max_concurrent_requests {
+            limits.push(RateLimit::concurrency(&name("concurrent_requests"), max));
+        }
+        limits.into_iter().map(|limit| limit.with_algorithm(algorithm)).collect()
+    }
+}
+
+// Policy file format
+#[derive(Deserialize, Debug, Default)]
+struct PolicyFile {
+    /// Group name -> members
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    policies: Vec<PolicyEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PolicyEntry {
+    #[serde(default)]
+    name: Option<String>,
+    /// User name pattern; absent matches everyone
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    /// Model name pattern
+    #[serde(default = "default_model_pattern")]
+    model: String,
+    #[serde(default)]
+    unlimited: bool,
+    #[serde(flatten)]
+    limits: LimitSettings,
+}
+
+fn default_model_pattern() -> String {
+    "*".to_string()
+}
+
+struct Policy {
+    user: Option<String>,
+    group: Option<String>,
+    model: String,
+    limits: Vec<RateLimit>,
+}
+
+/// Ordered rate limit policies keyed by user or group and model pattern.
+/// The first matching policy wins.
+#[derive(Default)]
+pub struct RateLimitPolicies {
+    /// User -> groups the user belongs to
+    memberships: HashMap<String, Vec<String>>,
+    policies: Vec<Policy>,
+}
+
+impl RateLimitPolicies {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let policy_file: PolicyFile = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path))?;
+        Ok(Self::from_file(policy_file))
+    }
+
+    fn from_file(policy_file: PolicyFile) -> Self {
+        let mut memberships: HashMap<String, Vec<String>> = HashMap::new();
+        for (group, members) in policy_file.groups {
+            for member in members {
+                memberships.entry(member).or_default().push(group.clone());
+            }
+        }
+
+        let policies = policy_file
+            .policies
+            .into_iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let name = entry.name.unwrap_or_else(|| format!("policy{}", index));
+                let limits = if entry.unlimited {
+                    Vec::new()
+                } else {
+                    entry.limits.to_limits(Some(&name))
+                };
+                Policy {
+                    user: entry.user,
+                    group: entry.group,
+                    model: entry.model,
+                    limits,
+                }
+            })
+            .collect();
+
+        Self {
+            memberships,
+            policies,
+        }
+    }
+
+    /// Limits of every policy.
+    pub fn limits(&self) -> impl Iterator<Item = &RateLimit> {
+        self.policies.iter().flat_map(|policy| &policy.limits)
+    }
+
+    /// Limits of the first policy matching the user and model, if any.
+    pub fn resolve(&self, user: &str, model: &str) -> Option<&[RateLimit]> {
+        let groups = self.memberships.get(user);
+        self.policies
+            .iter()
+            .find(|policy| {
+                let user_matches = policy.user.as_ref().is_none_or(|p| pattern::matches(p, user));
+                let group_matches = policy
+                    .group
+                    .as_ref()
+                    .is_none_or(|group| groups.is_some_and(|groups| groups.contains(group)));
+                user_matches && group_matches && pattern::matches(&policy.model, model)
+            })
+            .map(|policy| policy.limits.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PolicyFile, RateLimitPolicies};
+    use crate::limiter::Algorithm;
+
+    fn policies() -> RateLimitPolicies {
+        let policy_file: PolicyFile = serde_json::from_str(
+            r#"{
+                "groups": {"interns": ["alice", "bob"]},
+                "policies": [
+                    {"group": "interns", "model": "gpt-4o-mini", "unlimited": true},
+                    {"name": "interns-4o", "group": "interns", "model": "gpt-4o*", "tokens_per_minute": 50000},
+                    {"user": "svc-*", "requests_per_minute": 600, "max_concurrent_requests": 20, "algorithm": "gcra", "burst": 30},
+                    {"model": "gpt-4o-mini", "unlimited": true}
+                ]
+            }"#,
+        )
+        .unwrap();
+        RateLimitPolicies::from_file(policy_file)
+    }
+
+    #[test]
+    fn test_first_matching_policy_wins() {
+        let policies = policies();
+
+        assert_eq!(policies.resolve("alice", "gpt-4o-mini").map(|l| l.len()), Some(0));
+
+        let limits = policies.resolve("bob", "gpt-4o-2024-08-06").unwrap();
+        assert_eq!(limits.len(), 1);
+        assert_eq!(limits[0].name, "interns-4o:tokens_per_minute");
+        assert_eq!(limits[0].max, 50000);
+
+        let names: Vec<&str> = policies
+            .resolve("svc-batch", "o3")
+            .unwrap()
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["policy2:requests_per_minute", "policy2:concurrent_requests"]);
+
+        let limits = policies.resolve("svc-batch", "o3").unwrap();
+        assert_eq!(limits[0].algorithm, Algorithm::Gcra { burst: Some(30) });
+        assert_eq!(limits[0].capacity(), 30);
+        assert_eq!(limits[1].algorithm, Algorithm::SlidingWindow);
+    }
+
+    #[test]
+    fn test_unmatched_falls_through() {
+        let policies = policies();
+
+        assert_eq!(policies.resolve("carol", "gpt-4o-mini").map(|l| l.len()), Some(0));
+        assert!(policies.resolve("carol", "gpt-4o").is_none());
+        assert!(policies.resolve("alice", "o3").is_none());
+    }
+}
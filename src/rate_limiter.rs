@@ -1,63 +1,933 @@
-// use std::ops::DerefMut;
+use std::ops::DerefMut;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use async_trait::async_trait;
-// use deadpool::managed::Pool;
-// use redis::AsyncCommands;
+use deadpool::managed::{Pool, PoolConfig};
+use redis::Script;
+use rusqlite::{params, Connection, Transaction};
 
-// use crate::redis_async_pool::RedisConnectionManager;
+use crate::redis_async_pool::RedisConnectionManager;
 
-// const KEY_PREFIX: &str = "rate_limiter";
+pub const KEY_PREFIX: &str = "rate_limiter";
+
+/// Number of sub-buckets a sliding window is split into.
+const SUB_BUCKETS: u64 = 60;
+
+/// How often idle subjects are swept out of the in-memory limiter, and
+/// expired buckets compacted out of the SQLite one.
+pub(crate) const IDLE_SWEEP_INTERVAL_MS: u64 = 60_000;
+
+#[async_trait]
+pub trait SlidingWindowRateLimiter {
+    async fn record_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64>;
+
+    async fn fetch_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<u64>;
+
+    /// Like `fetch_sliding_window`, but also reports when capacity frees up.
+    async fn fetch_sliding_window_state(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<WindowState>;
+
+    /// Records an estimate up front so that concurrent requests see each
+    /// other before the real usage is known.
+    async fn reserve_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<Reservation>;
+
+    /// Replaces a reservation with the actual number of tokens used.
+    async fn commit_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64>;
+
+    /// Gives back a reservation whose request never completed.
+    async fn release_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        size: Duration,
+    ) -> Result<u64>;
+}
+
+/// Usage of a window together with the times at which it drains.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WindowState {
+    pub total: u64,
+    /// Until the oldest non-empty bucket slides out and frees some capacity
+    pub retry_after: Duration,
+    /// Until every bucket has slid out and the window is empty again
+    pub reset: Duration,
+}
+
+/// Tokens held in a window until the request that reserved them finishes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reservation {
+    /// Start (ms since epoch) of the sub-bucket holding the tokens
+    pub bucket: u64,
+    pub tokens: u64,
+    /// Window total including this reservation
+    pub total: u64,
+}
+
+/// Records tokens into the current sub-bucket, optionally giving back tokens
+/// held by an earlier reservation, and returns `{window total, current bucket}`.
+///
+/// The window is a hash of `bucket start (ms) -> tokens`; expired buckets are
+/// dropped on every call. Time comes from the Redis server so that all gateway
+/// replicas agree on bucket boundaries.
+///
+/// KEYS[1] - window key, ARGV[1] - window size (ms), ARGV[2] - bucket size (ms),
+/// ARGV[3] - tokens to record, ARGV[4] - reserved bucket, ARGV[5] - tokens to release
+const RECORD_SLIDING_WINDOW_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local size = tonumber(ARGV[1])
+local bucket = tonumber(ARGV[2])
+local tokens = tonumber(ARGV[3])
+local released = tonumber(ARGV[5])
+local current = now - (now % bucket)
+if released > 0 then
+    local held = tonumber(redis.call('HGET', KEYS[1], ARGV[4]) or 0)
+    if held <= released then
+        redis.call('HDEL', KEYS[1], ARGV[4])
+    else
+        redis.call('HINCRBY', KEYS[1], ARGV[4], -released)
+    end
+end
+local window_start = now - size
+local total = 0
+local fields = redis.call('HGETALL', KEYS[1])
+for i = 1, #fields, 2 do
+    if tonumber(fields[i]) + bucket <= window_start then
+        redis.call('HDEL', KEYS[1], fields[i])
+    else
+        total = total + tonumber(fields[i + 1])
+    end
+end
+if tokens > 0 then
+    redis.call('HINCRBY', KEYS[1], current, tokens)
+    total = total + tokens
+end
+redis.call('PEXPIRE', KEYS[1], size + bucket)
+return {total, current}
+"#;
+
+/// Returns `{window total, retry after (ms), reset (ms)}` without recording
+/// anything.
+///
+/// KEYS[1] - window key, ARGV[1] - window size (ms), ARGV[2] - bucket size (ms)
+const FETCH_SLIDING_WINDOW_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local size = tonumber(ARGV[1])
+local bucket = tonumber(ARGV[2])
+local window_start = now - size
+local total = 0
+local oldest = nil
+local newest = nil
+local fields = redis.call('HGETALL', KEYS[1])
+for i = 1, #fields, 2 do
+    local start = tonumber(fields[i])
+    local tokens = tonumber(fields[i + 1])
+    if start + bucket > window_start and tokens > 0 then
+        total = total + tokens
+        if oldest == nil or start < oldest then
+            oldest = start
+        end
+        if newest == nil or start > newest then
+            newest = start
+        end
+    end
+end
+if oldest == nil then
+    return {0, 0, 0}
+end
+return {total, oldest + bucket + size - now, newest + bucket + size - now}
+"#;
+
+/// Sliding-window limiter shared by every gateway replica through Redis.
+pub(crate) struct RedisSlidingWindowRateLimiter {
+    connection_pool: Pool<RedisConnectionManager>,
+    key_prefix: String,
+    record_script: Script,
+    fetch_script: Script,
+}
+
+impl RedisSlidingWindowRateLimiter {
+    pub fn new(redis_url: &str, pool_size: usize, key_prefix: &str) -> Result<Self> {
+        let manager = RedisConnectionManager::new(redis_url)?;
+        let connection_pool = Pool::builder(manager)
+            .config(PoolConfig::new(pool_size))
+            .build()?;
+        Ok(Self::from_pool(connection_pool, key_prefix))
+    }
+
+    pub fn from_pool(connection_pool: Pool<RedisConnectionManager>, key_prefix: &str) -> Self {
+        Self {
+            connection_pool,
+            key_prefix: key_prefix.to_string(),
+            record_script: Script::new(RECORD_SLIDING_WINDOW_SCRIPT),
+            fetch_script: Script::new(FETCH_SLIDING_WINDOW_SCRIPT),
+        }
+    }
+
+    fn key(&self, resource: &str, subject: &str, size: Duration) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.key_prefix,
+            resource,
+            subject,
+            size.as_millis()
+        )
+    }
+
+    async fn record(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        released: Option<&Reservation>,
+        size: Duration,
+    ) -> Result<(u64, u64)> {
+        let (size_ms, bucket_ms) = window_params(size);
+        let mut conn = self.connection_pool.get().await?;
+        let (total, bucket): (u64, u64) = self
+            .record_script
+            .key(self.key(resource, subject, size))
+            .arg(size_ms)
+            .arg(bucket_ms)
+            .arg(tokens)
+            .arg(released.map_or(0, |r| r.bucket))
+            .arg(released.map_or(0, |r| r.tokens))
+            .invoke_async(conn.deref_mut())
+            .await?;
+        Ok((total, bucket))
+    }
+}
+
+#[async_trait]
+impl SlidingWindowRateLimiter for RedisSlidingWindowRateLimiter {
+    async fn record_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64> {
+        let (total, _) = self.record(resource, subject, tokens, None, size).await?;
+        Ok(total)
+    }
+
+    async fn fetch_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<u64> {
+        Ok(self.fetch_sliding_window_state(resource, subject, size).await?.total)
+    }
+
+    async fn fetch_sliding_window_state(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<WindowState> {
+        let (size_ms, bucket_ms) = window_params(size);
+        let mut conn = self.connection_pool.get().await?;
+        let (total, retry_after, reset): (u64, u64, u64) = self
+            .fetch_script
+            .key(self.key(resource, subject, size))
+            .arg(size_ms)
+            .arg(bucket_ms)
+            .invoke_async(conn.deref_mut())
+            .await?;
+        Ok(WindowState {
+            total,
+            retry_after: Duration::from_millis(retry_after),
+            reset: Duration::from_millis(reset),
+        })
+    }
+
+    async fn reserve_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<Reservation> {
+        let (total, bucket) = self.record(resource, subject, tokens, None, size).await?;
+        Ok(Reservation {
+            bucket,
+            tokens,
+            total,
+        })
+    }
+
+    async fn commit_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64> {
+        let (total, _) = self
+            .record(resource, subject, tokens, Some(reservation), size)
+            .await?;
+        Ok(total)
+    }
+
+    async fn release_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        size: Duration,
+    ) -> Result<u64> {
+        let (total, _) = self
+            .record(resource, subject, 0, Some(reservation), size)
+            .await?;
+        Ok(total)
+    }
+}
+
+pub struct DummySlidingWindowRateLimiter {}
+
+#[async_trait]
+impl SlidingWindowRateLimiter for DummySlidingWindowRateLimiter {
+    async fn record_sliding_window(
+        &self,
+        _resource: &str,
+        _subject: &str,
+        _tokens: u64,
+        _size: Duration,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn fetch_sliding_window(
+        &self,
+        _resource: &str,
+        _subject: &str,
+        _size: Duration,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn fetch_sliding_window_state(
+        &self,
+        _resource: &str,
+        _subject: &str,
+        _size: Duration,
+    ) -> Result<WindowState> {
+        Ok(WindowState::default())
+    }
+
+    async fn reserve_sliding_window(
+        &self,
+        _resource: &str,
+        _subject: &str,
+        tokens: u64,
+        _size: Duration,
+    ) -> Result<Reservation> {
+        Ok(Reservation {
+            bucket: 0,
+            tokens,
+            total: 0,
+        })
+    }
+
+    async fn commit_sliding_window(
+        &self,
+        _resource: &str,
+        _subject: &str,
+        _reservation: &Reservation,
+        _tokens: u64,
+        _size: Duration,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn release_sliding_window(
+        &self,
+        _resource: &str,
+        _subject: &str,
+        _reservation: &Reservation,
+        _size: Duration,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+/// Window and sub-bucket sizes in milliseconds.
+fn window_params(size: Duration) -> (u64, u64) {
+    let size_ms = (size.as_millis() as u64).max(1);
+    (size_ms, (size_ms / SUB_BUCKETS).max(1))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A single (resource, subject) window, split into fixed-size sub-buckets.
+///
+/// A bucket is counted as long as any part of it overlaps the window, so the
+/// reported total errs on the side of over-counting by at most one bucket.
+struct SlidingWindow {
+    size_ms: u64,
+    bucket_ms: u64,
+    buckets: VecDeque<(u64, u64)>,
+    total: u64,
+}
+
+impl SlidingWindow {
+    fn new(size: Duration) -> Self {
+        let size_ms = (size.as_millis() as u64).max(1);
+        Self {
+            size_ms,
+            bucket_ms: (size_ms / SUB_BUCKETS).max(1),
+            buckets: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    fn evict(&mut self, now: u64) {
+        let window_start = now.saturating_sub(self.size_ms);
+        while let Some(&(start, tokens)) = self.buckets.front() {
+            if start + self.bucket_ms > window_start {
+                break;
+            }
+            self.total -= tokens;
+            self.buckets.pop_front();
+        }
+    }
+
+    fn current_bucket(&self, now: u64) -> u64 {
+        now - now % self.bucket_ms
+    }
+
+    fn record(&mut self, now: u64, tokens: u64) -> u64 {
+        self.evict(now);
+        let start = self.current_bucket(now);
+        match self.buckets.back_mut() {
+            Some((last, count)) if *last == start => *count += tokens,
+            _ => self.buckets.push_back((start, tokens)),
+        }
+        self.total += tokens;
+        self.total
+    }
+
+    fn fetch(&mut self, now: u64) -> u64 {
+        self.evict(now);
+        self.total
+    }
+
+    fn state(&mut self, now: u64) -> WindowState {
+        self.evict(now);
+        let mut used = self.buckets.iter().filter(|(_, tokens)| *tokens > 0);
+        let drained_at = |start: u64| Duration::from_millis(start + self.bucket_ms + self.size_ms - now);
+        let retry_after = used.next().map(|&(start, _)| drained_at(start));
+        let reset = used.next_back().map(|&(start, _)| drained_at(start)).or(retry_after);
+        WindowState {
+            total: self.total,
+            retry_after: retry_after.unwrap_or_default(),
+            reset: reset.unwrap_or_default(),
+        }
+    }
+
+    /// Takes reserved tokens back out of their bucket. Nothing happens if
+    /// the bucket has already slid out of the window.
+    fn release(&mut self, now: u64, reservation: &Reservation) -> u64 {
+        self.evict(now);
+        if let Some((_, held)) = self
+            .buckets
+            .iter_mut()
+            .find(|(start, _)| *start == reservation.bucket)
+        {
+            let released = reservation.tokens.min(*held);
+            *held -= released;
+            self.total -= released;
+        }
+        self.total
+    }
+
+    fn is_idle(&mut self, now: u64) -> bool {
+        self.evict(now);
+        self.buckets.is_empty()
+    }
+}
+
+type WindowKey = (String, String, u64);
+
+struct InMemoryState {
+    windows: HashMap<WindowKey, SlidingWindow>,
+    last_sweep: u64,
+}
+
+/// Process-local sliding-window limiter for single-node deployments.
+pub struct InMemorySlidingWindowRateLimiter {
+    state: Mutex<InMemoryState>,
+}
+
+impl Default for InMemorySlidingWindowRateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemorySlidingWindowRateLimiter {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(InMemoryState {
+                windows: HashMap::new(),
+                last_sweep: 0,
+            }),
+        }
+    }
+
+    fn key(resource: &str, subject: &str, size: Duration) -> WindowKey {
+        (resource.to_string(), subject.to_string(), size.as_millis() as u64)
+    }
+
+    fn sweep_idle(state: &mut InMemoryState, now: u64) {
+        if now.saturating_sub(state.last_sweep) < IDLE_SWEEP_INTERVAL_MS {
+            return;
+        }
+        state.last_sweep = now;
+        state.windows.retain(|_, window| !window.is_idle(now));
+    }
+
+    fn with_window<T>(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+        now: u64,
+        f: impl FnOnce(&mut SlidingWindow) -> T,
+    ) -> T {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        Self::sweep_idle(&mut state, now);
+        let window = state
+            .windows
+            .entry(Self::key(resource, subject, size))
+            .or_insert_with(|| SlidingWindow::new(size));
+        f(window)
+    }
+
+    fn record_at(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+        now: u64,
+    ) -> u64 {
+        self.with_window(resource, subject, size, now, |window| window.record(now, tokens))
+    }
+
+    fn reserve_at(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+        now: u64,
+    ) -> Reservation {
+        self.with_window(resource, subject, size, now, |window| Reservation {
+            total: window.record(now, tokens),
+            bucket: window.current_bucket(now),
+            tokens,
+        })
+    }
+
+    fn commit_at(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        tokens: u64,
+        size: Duration,
+        now: u64,
+    ) -> u64 {
+        self.with_window(resource, subject, size, now, |window| {
+            window.release(now, reservation);
+            window.record(now, tokens)
+        })
+    }
+
+    fn release_at(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        size: Duration,
+        now: u64,
+    ) -> u64 {
+        self.with_window(resource, subject, size, now, |window| window.release(now, reservation))
+    }
+
+    fn fetch_at(&self, resource: &str, subject: &str, size: Duration, now: u64) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        Self::sweep_idle(&mut state, now);
+        state
+            .windows
+            .get_mut(&Self::key(resource, subject, size))
+            .map_or(0, |window| window.fetch(now))
+    }
+
+    fn fetch_state_at(&self, resource: &str, subject: &str, size: Duration, now: u64) -> WindowState {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        Self::sweep_idle(&mut state, now);
+        state
+            .windows
+            .get_mut(&Self::key(resource, subject, size))
+            .map_or_else(WindowState::default, |window| window.state(now))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).windows.len()
+    }
+}
+
+#[async_trait]
+impl SlidingWindowRateLimiter for InMemorySlidingWindowRateLimiter {
+    async fn record_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64> {
+        Ok(self.record_at(resource, subject, tokens, size, now_millis()))
+    }
+
+    async fn fetch_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<u64> {
+        Ok(self.fetch_at(resource, subject, size, now_millis()))
+    }
+
+    async fn fetch_sliding_window_state(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<WindowState> {
+        Ok(self.fetch_state_at(resource, subject, size, now_millis()))
+    }
+
+    async fn reserve_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<Reservation> {
+        Ok(self.reserve_at(resource, subject, tokens, size, now_millis()))
+    }
+
+    async fn commit_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64> {
+        Ok(self.commit_at(resource, subject, reservation, tokens, size, now_millis()))
+    }
+
+    async fn release_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        size: Duration,
+    ) -> Result<u64> {
+        Ok(self.release_at(resource, subject, reservation, size, now_millis()))
+    }
+}
+
+/// One row per non-empty sub-bucket, keyed like the Redis windows. Rows stay
+/// until compaction removes them after `expires_at`.
+const SQLITE_SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+PRAGMA auto_vacuum = INCREMENTAL;
+CREATE TABLE IF NOT EXISTS sliding_window_buckets (
+    key TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    tokens INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (key, bucket)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS sliding_window_buckets_expires_at
+    ON sliding_window_buckets (expires_at);
+";
+
+struct SqliteState {
+    connection: Connection,
+    last_compaction: u64,
+}
+
+/// Sliding-window limiter persisted in a local SQLite file, so that
+/// single-node deployments keep their quotas across restarts. Queries block,
+/// so they run on Tokio's blocking pool rather than on the async workers.
+#[derive(Clone)]
+pub struct SqliteSlidingWindowRateLimiter {
+    state: Arc<Mutex<SqliteState>>,
+}
+
+impl SqliteSlidingWindowRateLimiter {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn from_connection(connection: Connection) -> Result<Self> {
+        connection.execute_batch(SQLITE_SCHEMA)?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(SqliteState {
+                connection,
+                last_compaction: 0,
+            })),
+        })
+    }
+
+    fn key(resource: &str, subject: &str, size: Duration) -> String {
+        format!("{}:{}:{}", resource, subject, size.as_millis())
+    }
+
+    /// Drops buckets that slid out of their window and hands the freed pages
+    /// back to the file system.
+    fn compact(connection: &Connection, now: u64) -> rusqlite::Result<usize> {
+        let removed = connection.execute(
+            "DELETE FROM sliding_window_buckets WHERE expires_at <= ?1",
+            params![now],
+        )?;
+        connection.execute_batch("PRAGMA incremental_vacuum")?;
+        Ok(removed)
+    }
+
+    fn with_transaction<T>(
+        &self,
+        now: u64,
+        f: impl FnOnce(&Transaction) -> rusqlite::Result<T>,
+    ) -> Result<T> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_sub(state.last_compaction) >= IDLE_SWEEP_INTERVAL_MS {
+            state.last_compaction = now;
+            Self::compact(&state.connection, now)?;
+        }
+        let tx = state.connection.transaction()?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Takes reserved tokens back out of their bucket, if it is still stored.
+    fn take(connection: &Connection, key: &str, reservation: &Reservation) -> rusqlite::Result<()> {
+        connection.execute(
+            "UPDATE sliding_window_buckets SET tokens = max(tokens - ?3, 0) WHERE key = ?1 AND bucket = ?2",
+            params![key, reservation.bucket, reservation.tokens],
+        )?;
+        connection.execute(
+            "DELETE FROM sliding_window_buckets WHERE key = ?1 AND bucket = ?2 AND tokens = 0",
+            params![key, reservation.bucket],
+        )?;
+        Ok(())
+    }
+
+    fn window_state(
+        connection: &Connection,
+        key: &str,
+        now: u64,
+        size_ms: u64,
+        bucket_ms: u64,
+    ) -> rusqlite::Result<WindowState> {
+        let (total, oldest, newest): (u64, Option<u64>, Option<u64>) = connection.query_row(
+            "SELECT COALESCE(SUM(tokens), 0), MIN(bucket), MAX(bucket) FROM sliding_window_buckets
+             WHERE key = ?1 AND bucket + ?2 > ?3 AND tokens > 0",
+            params![key, bucket_ms, now.saturating_sub(size_ms)],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let drained_at = |start: u64| Duration::from_millis(start + bucket_ms + size_ms - now);
+        Ok(WindowState {
+            total,
+            retry_after: oldest.map(drained_at).unwrap_or_default(),
+            reset: newest.map(drained_at).unwrap_or_default(),
+        })
+    }
+
+    fn record_at(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        released: Option<&Reservation>,
+        size: Duration,
+        now: u64,
+    ) -> Result<Reservation> {
+        let key = Self::key(resource, subject, size);
+        let (size_ms, bucket_ms) = window_params(size);
+        let bucket = now - now % bucket_ms;
+        self.with_transaction(now, |tx| {
+            if let Some(reservation) = released {
+                Self::take(tx, &key, reservation)?;
+            }
+            if tokens > 0 {
+                tx.execute(
+                    "INSERT INTO sliding_window_buckets (key, bucket, tokens, expires_at) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (key, bucket) DO UPDATE SET tokens = tokens + excluded.tokens",
+                    params![key, bucket, tokens, bucket + bucket_ms + size_ms],
+                )?;
+            }
+            let total = Self::window_state(tx, &key, now, size_ms, bucket_ms)?.total;
+            Ok(Reservation {
+                bucket,
+                tokens,
+                total,
+            })
+        })
+    }
+
+    fn fetch_state_at(&self, resource: &str, subject: &str, size: Duration, now: u64) -> Result<WindowState> {
+        let key = Self::key(resource, subject, size);
+        let (size_ms, bucket_ms) = window_params(size);
+        self.with_transaction(now, |tx| Self::window_state(tx, &key, now, size_ms, bucket_ms))
+    }
+
+    async fn record(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        released: Option<&Reservation>,
+        size: Duration,
+    ) -> Result<Reservation> {
+        let (limiter, resource, subject, released) =
+            (self.clone(), resource.to_string(), subject.to_string(), released.cloned());
+        tokio::task::spawn_blocking(move || {
+            limiter.record_at(&resource, &subject, tokens, released.as_ref(), size, now_millis())
+        })
+        .await?
+    }
+
+    async fn fetch_state(&self, resource: &str, subject: &str, size: Duration) -> Result<WindowState> {
+        let (limiter, resource, subject) = (self.clone(), resource.to_string(), subject.to_string());
+        tokio::task::spawn_blocking(move || limiter.fetch_state_at(&resource, &subject, size, now_millis()))
+            .await?
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .connection
+            .query_row("SELECT COUNT(*) FROM sliding_window_buckets", [], |row| row.get(0))
+            .unwrap()
+    }
+}
 
 #[async_trait]
-pub trait SlidingWindowRateLimiter {
+impl SlidingWindowRateLimiter for SqliteSlidingWindowRateLimiter {
     async fn record_sliding_window(
         &self,
         resource: &str,
         subject: &str,
         tokens: u64,
         size: Duration,
-    ) -> Result<u64>;
+    ) -> Result<u64> {
+        Ok(self.record(resource, subject, tokens, None, size).await?.total)
+    }
 
     async fn fetch_sliding_window(
         &self,
         resource: &str,
         subject: &str,
         size: Duration,
-    ) -> Result<u64>;
-}
+    ) -> Result<u64> {
+        Ok(self.fetch_state(resource, subject, size).await?.total)
+    }
 
-// pub(crate) struct RedisSlidingWindowRateLimiter {
-//     connection_pool: Pool<RedisConnectionManager>,
-// }
+    async fn fetch_sliding_window_state(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<WindowState> {
+        self.fetch_state(resource, subject, size).await
+    }
 
-pub struct DummySlidingWindowRateLimiter {}
+    async fn reserve_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<Reservation> {
+        self.record(resource, subject, tokens, None, size).await
+    }
 
-#[async_trait]
-impl SlidingWindowRateLimiter for DummySlidingWindowRateLimiter {
-    async fn record_sliding_window(
+    async fn commit_sliding_window(
         &self,
-        _resource: &str,
-        _subject: &str,
-        _tokens: u64,
-        _size: Duration,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        tokens: u64,
+        size: Duration,
     ) -> Result<u64> {
-        Ok(0)
+        Ok(self.record(resource, subject, tokens, Some(reservation), size).await?.total)
     }
 
-    async fn fetch_sliding_window(
+    async fn release_sliding_window(
         &self,
-        _resource: &str,
-        _subject: &str,
-        _size: Duration,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        size: Duration,
     ) -> Result<u64> {
-        Ok(0)
+        Ok(self.record(resource, subject, 0, Some(reservation), size).await?.total)
     }
 }
 
 pub(crate) enum SlidingWindowRateLimiterEnum {
-    // Redis(RedisSlidingWindowRateLimiter),
+    Redis(RedisSlidingWindowRateLimiter),
+    InMemory(InMemorySlidingWindowRateLimiter),
+    Sqlite(SqliteSlidingWindowRateLimiter),
     Dummy(DummySlidingWindowRateLimiter),
 }
 
@@ -71,11 +941,21 @@ impl SlidingWindowRateLimiter for SlidingWindowRateLimiterEnum {
         size: Duration,
     ) -> Result<u64> {
         match self {
-            // SlidingWindowRateLimiterEnum::Redis(redis) => {
-            //     redis
-            //         .record_sliding_window(resource, subject, tokens, size)
-            //         .await
-            // }
+            SlidingWindowRateLimiterEnum::Redis(redis) => {
+                redis
+                    .record_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::InMemory(memory) => {
+                memory
+                    .record_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Sqlite(sqlite) => {
+                sqlite
+                    .record_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
             SlidingWindowRateLimiterEnum::Dummy(dummy) => {
                 dummy
                     .record_sliding_window(resource, subject, tokens, size)
@@ -91,19 +971,142 @@ impl SlidingWindowRateLimiter for SlidingWindowRateLimiterEnum {
         size: Duration,
     ) -> Result<u64> {
         match self {
-            // SlidingWindowRateLimiterEnum::Redis(redis) => {
-            //     redis.fetch_sliding_window(resource, subject, size).await
-            // }
+            SlidingWindowRateLimiterEnum::Redis(redis) => {
+                redis.fetch_sliding_window(resource, subject, size).await
+            }
+            SlidingWindowRateLimiterEnum::InMemory(memory) => {
+                memory.fetch_sliding_window(resource, subject, size).await
+            }
+            SlidingWindowRateLimiterEnum::Sqlite(sqlite) => {
+                sqlite.fetch_sliding_window(resource, subject, size).await
+            }
             SlidingWindowRateLimiterEnum::Dummy(dummy) => {
                 dummy.fetch_sliding_window(resource, subject, size).await
             }
         }
     }
+
+    async fn fetch_sliding_window_state(
+        &self,
+        resource: &str,
+        subject: &str,
+        size: Duration,
+    ) -> Result<WindowState> {
+        match self {
+            SlidingWindowRateLimiterEnum::Redis(redis) => {
+                redis.fetch_sliding_window_state(resource, subject, size).await
+            }
+            SlidingWindowRateLimiterEnum::InMemory(memory) => {
+                memory.fetch_sliding_window_state(resource, subject, size).await
+            }
+            SlidingWindowRateLimiterEnum::Sqlite(sqlite) => {
+                sqlite.fetch_sliding_window_state(resource, subject, size).await
+            }
+            SlidingWindowRateLimiterEnum::Dummy(dummy) => {
+                dummy.fetch_sliding_window_state(resource, subject, size).await
+            }
+        }
+    }
+
+    async fn reserve_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<Reservation> {
+        match self {
+            SlidingWindowRateLimiterEnum::Redis(redis) => {
+                redis
+                    .reserve_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::InMemory(memory) => {
+                memory
+                    .reserve_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Sqlite(sqlite) => {
+                sqlite
+                    .reserve_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Dummy(dummy) => {
+                dummy
+                    .reserve_sliding_window(resource, subject, tokens, size)
+                    .await
+            }
+        }
+    }
+
+    async fn commit_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        tokens: u64,
+        size: Duration,
+    ) -> Result<u64> {
+        match self {
+            SlidingWindowRateLimiterEnum::Redis(redis) => {
+                redis
+                    .commit_sliding_window(resource, subject, reservation, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::InMemory(memory) => {
+                memory
+                    .commit_sliding_window(resource, subject, reservation, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Sqlite(sqlite) => {
+                sqlite
+                    .commit_sliding_window(resource, subject, reservation, tokens, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Dummy(dummy) => {
+                dummy
+                    .commit_sliding_window(resource, subject, reservation, tokens, size)
+                    .await
+            }
+        }
+    }
+
+    async fn release_sliding_window(
+        &self,
+        resource: &str,
+        subject: &str,
+        reservation: &Reservation,
+        size: Duration,
+    ) -> Result<u64> {
+        match self {
+            SlidingWindowRateLimiterEnum::Redis(redis) => {
+                redis
+                    .release_sliding_window(resource, subject, reservation, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::InMemory(memory) => {
+                memory
+                    .release_sliding_window(resource, subject, reservation, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Sqlite(sqlite) => {
+                sqlite
+                    .release_sliding_window(resource, subject, reservation, size)
+                    .await
+            }
+            SlidingWindowRateLimiterEnum::Dummy(dummy) => {
+                dummy
+                    .release_sliding_window(resource, subject, reservation, size)
+                    .await
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::process::{Child, Command, Stdio};
     use std::time::Duration;
 
     use deadpool::managed::{Pool, PoolConfig};
@@ -111,15 +1114,16 @@ mod tests {
     use testcontainers::{
         core::{IntoContainerPort, WaitFor},
         runners::AsyncRunner,
-        GenericImage, ImageExt,
+        ContainerAsync, GenericImage, ImageExt,
     };
 
     use crate::rate_limiter;
     use crate::rate_limiter::{
-        DummySlidingWindowRateLimiter, SlidingWindowRateLimiter,
-        SlidingWindowRateLimiterEnum,
+        DummySlidingWindowRateLimiter, InMemorySlidingWindowRateLimiter,
+        RedisSlidingWindowRateLimiter, SlidingWindowRateLimiter, SlidingWindowRateLimiterEnum,
+        SqliteSlidingWindowRateLimiter, WindowState,
     };
-    // use crate::redis_async_pool::RedisConnectionManager;
+    use crate::redis_async_pool::RedisConnectionManager;
 
 
     #[tokio::test]
@@ -155,6 +1159,380 @@ mod tests {
         assert_eq!(count, 0);
     }
 
+    #[tokio::test]
+    async fn test_enum_in_memory_rate_limiter() {
+        let rate_limiter =
+            SlidingWindowRateLimiterEnum::InMemory(InMemorySlidingWindowRateLimiter::new());
+        let count = rate_limiter
+            .record_sliding_window("user", "test-user-1", 10, Duration::from_secs(60))
+            .await
+            .expect("Failed to record sliding window");
+        assert_eq!(count, 10);
+
+        let count = rate_limiter
+            .record_sliding_window("user", "test-user-1", 5, Duration::from_secs(60))
+            .await
+            .expect("Failed to record sliding window");
+        assert_eq!(count, 15);
+
+        let count = rate_limiter
+            .fetch_sliding_window("user", "test-user-2", Duration::from_secs(60))
+            .await
+            .expect("Failed to fetch sliding window");
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_in_memory_window_slides() {
+        let rate_limiter = InMemorySlidingWindowRateLimiter::new();
+        let size = Duration::from_secs(60);
+
+        // Buckets are one second wide for a one minute window.
+        assert_eq!(rate_limiter.record_at("user", "u", 10, size, 1_000), 10);
+        assert_eq!(rate_limiter.record_at("user", "u", 20, size, 30_000), 30);
+        assert_eq!(rate_limiter.fetch_at("user", "u", size, 60_500), 30);
+        assert_eq!(rate_limiter.fetch_at("user", "u", size, 61_000), 30);
+        assert_eq!(rate_limiter.fetch_at("user", "u", size, 62_000), 20);
+        assert_eq!(rate_limiter.fetch_at("user", "u", size, 91_000), 0);
+    }
+
+    #[test]
+    fn test_in_memory_reservation_commit_and_release() {
+        let rate_limiter = InMemorySlidingWindowRateLimiter::new();
+        let size = Duration::from_secs(60);
+
+        let first = rate_limiter.reserve_at("user", "u", 100, size, 1_000);
+        assert_eq!(first.total, 100);
+        let second = rate_limiter.reserve_at("user", "u", 50, size, 5_000);
+        assert_eq!(second.total, 150);
+
+        // The first request used less than it reserved.
+        assert_eq!(rate_limiter.commit_at("user", "u", &first, 30, size, 10_000), 80);
+        // The second one never finished.
+        assert_eq!(rate_limiter.release_at("user", "u", &second, size, 11_000), 30);
+
+        // Releasing twice must not eat into other usage.
+        assert_eq!(rate_limiter.release_at("user", "u", &second, size, 12_000), 30);
+    }
+
+    #[test]
+    fn test_in_memory_commit_after_reservation_expired() {
+        let rate_limiter = InMemorySlidingWindowRateLimiter::new();
+        let size = Duration::from_secs(60);
+
+        let reservation = rate_limiter.reserve_at("user", "u", 100, size, 1_000);
+        assert_eq!(rate_limiter.commit_at("user", "u", &reservation, 40, size, 90_000), 40);
+    }
+
+    #[test]
+    fn test_in_memory_window_state() {
+        let rate_limiter = InMemorySlidingWindowRateLimiter::new();
+        let size = Duration::from_secs(60);
+
+        assert_eq!(rate_limiter.fetch_state_at("user", "u", size, 1_000), WindowState::default());
+
+        rate_limiter.record_at("user", "u", 10, size, 1_000);
+        rate_limiter.record_at("user", "u", 20, size, 30_000);
+        assert_eq!(
+            rate_limiter.fetch_state_at("user", "u", size, 40_000),
+            WindowState {
+                total: 30,
+                retry_after: Duration::from_millis(22_000),
+                reset: Duration::from_millis(51_000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_in_memory_windows_are_isolated() {
+        let rate_limiter = InMemorySlidingWindowRateLimiter::new();
+        let minute = Duration::from_secs(60);
+        let hour = Duration::from_secs(3600);
+
+        rate_limiter.record_at("user", "a", 10, minute, 1_000);
+        rate_limiter.record_at("user", "a", 7, hour, 1_000);
+        rate_limiter.record_at("model", "a", 3, minute, 1_000);
+
+        assert_eq!(rate_limiter.fetch_at("user", "a", minute, 2_000), 10);
+        assert_eq!(rate_limiter.fetch_at("user", "a", hour, 2_000), 7);
+        assert_eq!(rate_limiter.fetch_at("model", "a", minute, 2_000), 3);
+        assert_eq!(rate_limiter.fetch_at("user", "b", minute, 2_000), 0);
+    }
+
+    #[test]
+    fn test_in_memory_idle_subjects_expire() {
+        let rate_limiter = InMemorySlidingWindowRateLimiter::new();
+        let size = Duration::from_secs(10);
+
+        rate_limiter.record_at("user", "idle", 1, size, 1_000);
+        rate_limiter.record_at("user", "active", 1, size, 58_000);
+        assert_eq!(rate_limiter.len(), 2);
+
+        // The first sweep runs once a minute has passed since the last one.
+        rate_limiter.record_at("user", "active", 1, size, 65_000);
+        assert_eq!(rate_limiter.len(), 1);
+        assert_eq!(rate_limiter.fetch_at("user", "active", size, 65_000), 2);
+    }
+
+    fn sqlite_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rate_limiter_{}_{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_sqlite_window_slides() {
+        let rate_limiter = SqliteSlidingWindowRateLimiter::open(":memory:").unwrap();
+        let size = Duration::from_secs(60);
+
+        let record = |tokens, now| rate_limiter.record_at("user", "u", tokens, None, size, now).unwrap().total;
+        let fetch = |now| rate_limiter.fetch_state_at("user", "u", size, now).unwrap().total;
+        assert_eq!(record(10, 1_000), 10);
+        assert_eq!(record(20, 30_000), 30);
+        assert_eq!(fetch(61_000), 30);
+        assert_eq!(fetch(62_000), 20);
+        assert_eq!(fetch(91_000), 0);
+    }
+
+    #[test]
+    fn test_sqlite_reservation_commit_and_release() {
+        let rate_limiter = SqliteSlidingWindowRateLimiter::open(":memory:").unwrap();
+        let size = Duration::from_secs(60);
+
+        let first = rate_limiter.record_at("user", "u", 100, None, size, 1_000).unwrap();
+        assert_eq!(first.total, 100);
+        let second = rate_limiter.record_at("user", "u", 50, None, size, 5_000).unwrap();
+        assert_eq!(second.total, 150);
+
+        let commit = rate_limiter.record_at("user", "u", 30, Some(&first), size, 10_000).unwrap();
+        assert_eq!(commit.total, 80);
+        let release = rate_limiter.record_at("user", "u", 0, Some(&second), size, 11_000).unwrap();
+        assert_eq!(release.total, 30);
+        let release = rate_limiter.record_at("user", "u", 0, Some(&second), size, 12_000).unwrap();
+        assert_eq!(release.total, 30);
+
+        assert_eq!(
+            rate_limiter.fetch_state_at("user", "u", size, 40_000).unwrap(),
+            WindowState {
+                total: 30,
+                retry_after: Duration::from_millis(31_000),
+                reset: Duration::from_millis(31_000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_sqlite_survives_restart() {
+        let path = sqlite_path("restart");
+        let size = Duration::from_secs(60);
+
+        let rate_limiter = SqliteSlidingWindowRateLimiter::open(&path).unwrap();
+        rate_limiter.record_at("user", "u", 42, None, size, 1_000).unwrap();
+        drop(rate_limiter);
+
+        let rate_limiter = SqliteSlidingWindowRateLimiter::open(&path).unwrap();
+        assert_eq!(rate_limiter.fetch_state_at("user", "u", size, 2_000).unwrap().total, 42);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_compacts_expired_buckets() {
+        let rate_limiter = SqliteSlidingWindowRateLimiter::open(":memory:").unwrap();
+        let size = Duration::from_secs(10);
+
+        rate_limiter.record_at("user", "idle", 1, None, size, 1_000).unwrap();
+        rate_limiter.record_at("user", "active", 1, None, size, 58_000).unwrap();
+        assert_eq!(rate_limiter.len(), 2);
+
+        // Compaction runs once a minute has passed since the last one and
+        // drops the idle bucket before the new one is added.
+        rate_limiter.record_at("user", "active", 1, None, size, 65_000).unwrap();
+        assert_eq!(rate_limiter.len(), 2);
+        assert_eq!(rate_limiter.fetch_state_at("user", "active", size, 65_000).unwrap().total, 2);
+        assert_eq!(rate_limiter.fetch_state_at("user", "idle", size, 65_000).unwrap().total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_runs_on_blocking_pool() {
+        let rate_limiter = SqliteSlidingWindowRateLimiter::open(":memory:").unwrap();
+        let size = Duration::from_secs(60);
+
+        let reservation = rate_limiter.reserve_sliding_window("user", "u", 100, size).await.unwrap();
+        assert_eq!(reservation.total, 100);
+        let total = rate_limiter.commit_sliding_window("user", "u", &reservation, 40, size).await.unwrap();
+        assert_eq!(total, 40);
+        assert_eq!(rate_limiter.fetch_sliding_window("user", "u", size).await.unwrap(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_redis_rate_limiter_shared_between_replicas() {
+        let (_redis, url) = start_redis().await;
+        let replica_a = redis_rate_limiter(&url, "test");
+        let replica_b = redis_rate_limiter(&url, "test");
+        let size = Duration::from_secs(60);
+
+        let count = replica_a
+            .record_sliding_window("user", "test-user-1", 10, size)
+            .await
+            .expect("Failed to record sliding window");
+        assert_eq!(count, 10);
+
+        let count = replica_b
+            .record_sliding_window("user", "test-user-1", 5, size)
+            .await
+            .expect("Failed to record sliding window");
+        assert_eq!(count, 15);
+
+        let count = replica_a
+            .fetch_sliding_window("user", "test-user-1", size)
+            .await
+            .expect("Failed to fetch sliding window");
+        assert_eq!(count, 15);
+
+        let count = replica_a
+            .fetch_sliding_window("user", "test-user-2", size)
+            .await
+            .expect("Failed to fetch sliding window");
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_redis_rate_limiter_reservations() {
+        let (_redis, url) = start_redis().await;
+        let rate_limiter = redis_rate_limiter(&url, "test");
+        let size = Duration::from_secs(60);
+
+        let first = rate_limiter
+            .reserve_sliding_window("user", "test-user-1", 100, size)
+            .await
+            .expect("Failed to reserve sliding window");
+        assert_eq!(first.total, 100);
+        let second = rate_limiter
+            .reserve_sliding_window("user", "test-user-1", 50, size)
+            .await
+            .expect("Failed to reserve sliding window");
+        assert_eq!(second.total, 150);
+
+        let count = rate_limiter
+            .commit_sliding_window("user", "test-user-1", &first, 30, size)
+            .await
+            .expect("Failed to commit sliding window");
+        assert_eq!(count, 80);
+
+        let count = rate_limiter
+            .release_sliding_window("user", "test-user-1", &second, size)
+            .await
+            .expect("Failed to release sliding window");
+        assert_eq!(count, 30);
+    }
+
+    #[tokio::test]
+    async fn test_redis_rate_limiter_key_prefix() {
+        let (_redis, url) = start_redis().await;
+        let size = Duration::from_secs(60);
+
+        redis_rate_limiter(&url, "gateway-a")
+            .record_sliding_window("user", "test-user-1", 10, size)
+            .await
+            .expect("Failed to record sliding window");
+        let count = redis_rate_limiter(&url, "gateway-b")
+            .fetch_sliding_window("user", "test-user-1", size)
+            .await
+            .expect("Failed to fetch sliding window");
+        assert_eq!(count, 0);
+
+        let mut conn = Client::open(url.as_str())
+            .unwrap()
+            .get_multiplexed_async_connection()
+            .await
+            .unwrap();
+        let keys: Vec<String> = redis::cmd("KEYS").arg("*").query_async(&mut conn).await.unwrap();
+        assert_eq!(keys, vec!["gateway-a:user:test-user-1:60000".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_redis_rate_limiter_window_expires() {
+        let (_redis, url) = start_redis().await;
+        let rate_limiter = SlidingWindowRateLimiterEnum::Redis(redis_rate_limiter(&url, "test"));
+        let size = Duration::from_millis(500);
+
+        rate_limiter
+            .record_sliding_window("user", "test-user-1", 10, size)
+            .await
+            .expect("Failed to record sliding window");
+        tokio::time::sleep(Duration::from_millis(600)).await;
+
+        let count = rate_limiter
+            .fetch_sliding_window("user", "test-user-1", size)
+            .await
+            .expect("Failed to fetch sliding window");
+        assert_eq!(count, 0);
+    }
+
+    fn redis_rate_limiter(url: &str, key_prefix: &str) -> RedisSlidingWindowRateLimiter {
+        let manager = RedisConnectionManager::new(url).unwrap();
+        let pool = Pool::builder(manager).config(PoolConfig::new(2)).build().unwrap();
+        RedisSlidingWindowRateLimiter::from_pool(pool, key_prefix)
+    }
+
+    /// Keeps the Redis instance backing a test alive until dropped.
+    struct RedisGuard {
+        process: Option<Child>,
+        _container: Option<ContainerAsync<GenericImage>>,
+    }
+
+    impl Drop for RedisGuard {
+        fn drop(&mut self) {
+            if let Some(child) = self.process.as_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    /// Starts a throwaway Redis, preferring a local `redis-server` binary and
+    /// falling back to a container.
+    async fn start_redis() -> (RedisGuard, String) {
+        let port = find_free_port();
+        let url = format!("redis://127.0.0.1:{}/0", port);
+
+        let process = Command::new("redis-server")
+            .args(["--port", &port.to_string(), "--save", "", "--appendonly", "no"])
+            .stdout(Stdio::null())
+            .spawn();
+        let guard = match process {
+            Ok(child) => RedisGuard {
+                process: Some(child),
+                _container: None,
+            },
+            Err(_) => RedisGuard {
+                process: None,
+                _container: Some(
+                    GenericImage::new("redis", "7.2.4")
+                        .with_exposed_port(6379.tcp())
+                        .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"))
+                        .with_mapped_port(port, 6379.tcp())
+                        .start()
+                        .await
+                        .expect("Redis tests need either redis-server or Docker"),
+                ),
+            },
+        };
+
+        let client = Client::open(url.as_str()).unwrap();
+        for _ in 0..50 {
+            if let Ok(mut conn) = client.get_multiplexed_async_connection().await {
+                let pong: redis::RedisResult<String> =
+                    redis::cmd("PING").query_async(&mut conn).await;
+                if pong.is_ok() {
+                    return (guard, url);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Redis did not become ready on port {}", port);
+    }
+
     fn find_free_port() -> u16 {
         let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
         listener.local_addr().unwrap().port()
@@ -0,0 +1,38 @@
+use deadpool::managed::{self, Metrics, RecycleError, RecycleResult};
+use redis::aio::MultiplexedConnection;
+use redis::{Client, IntoConnectionInfo, RedisError, RedisResult};
+
+/// deadpool manager handing out multiplexed async Redis connections.
+pub struct RedisConnectionManager {
+    client: Client,
+}
+
+impl RedisConnectionManager {
+    pub fn new<T: IntoConnectionInfo>(params: T) -> RedisResult<Self> {
+        Ok(Self {
+            client: Client::open(params)?,
+        })
+    }
+}
+
+impl managed::Manager for RedisConnectionManager {
+    type Type = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<MultiplexedConnection, RedisError> {
+        self.client.get_multiplexed_async_connection().await
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut MultiplexedConnection,
+        _: &Metrics,
+    ) -> RecycleResult<RedisError> {
+        let pong: String = redis::cmd("PING").query_async(conn).await?;
+        if pong == "PONG" {
+            Ok(())
+        } else {
+            Err(RecycleError::message("Invalid PING response"))
+        }
+    }
+}
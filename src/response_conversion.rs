@@ -0,0 +1,218 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::api_format::ApiFormat;
+use crate::error::anthropic_error_type;
+use crate::stream_conversion::{anthropic_stop_reason, openai_finish_reason};
+
+/// Converts a complete, non-streamed chat response between API formats.
+pub fn convert_response(body: &[u8], from: ApiFormat, to: ApiFormat) -> serde_json::Result<Vec<u8>> {
+    let response: Value = serde_json::from_slice(body)?;
+    let converted = match (from, to) {
+        (ApiFormat::OpenAI, ApiFormat::Anthropic) => openai_to_anthropic(&response),
+        (ApiFormat::Anthropic, ApiFormat::OpenAI) => anthropic_to_openai(&response),
+        _ => response,
+    };
+    serde_json::to_vec(&converted)
+}
+
+/// Converts an upstream error body between API formats, keeping its message
+/// and, towards OpenAI, its type and code. Bodies that are not JSON become
+/// the message.
+pub fn convert_error(body: &[u8], status: u16, from: ApiFormat, to: ApiFormat) -> Vec<u8> {
+    if from == to {
+        return body.to_vec();
+    }
+    let error = serde_json::from_slice::<Value>(body)
+        .ok()
+        .map(|response| response["error"].clone())
+        .filter(Value::is_object);
+    let message = match &error {
+        Some(error) => error["message"].as_str().unwrap_or_default().to_string(),
+        None => String::from_utf8_lossy(body).trim().to_string(),
+    };
+    let error = error.unwrap_or_default();
+    let converted = match to {
+        ApiFormat::OpenAI => json!({"error": {
+            "message": message,
+            "type": error["type"].as_str().unwrap_or("upstream_error"),
+            "param": error.get("param"),
+            "code": error.get("code"),
+        }}),
+        ApiFormat::Anthropic => json!({
+            "type": "error",
+            "error": {"type": anthropic_error_type(status), "message": message},
+        }),
+    };
+    converted.to_string().into_bytes()
+}
+
+/// `chat.completion` to an Anthropic `message`.
+fn openai_to_anthropic(response: &Value) -> Value {
+    let choice = &response["choices"][0];
+    let message = &choice["message"];
+
+    let mut content = Vec::new();
+    if let Some(thinking) = message["reasoning_content"].as_str().filter(|t| !t.is_empty()) {
+        content.push(json!({"type": "thinking", "thinking": thinking}));
+    }
+    if let Some(text) = message["content"].as_str().filter(|t| !t.is_empty()) {
+        content.push(json!({"type": "text", "text": text}));
+    }
+    for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+        let function = &tool_call["function"];
+        // Arguments are a JSON string in OpenAI and an object in Anthropic
+        let input = function["arguments"]
+            .as_str()
+            .and_then(|arguments| serde_json::from_str(arguments).ok())
+            .unwrap_or_else(|| json!({}));
+        content.push(json!({
+            "type": "tool_use",
+            "id": tool_call["id"],
+            "name": function["name"],
+            "input": input,
+        }));
+    }
+
+    json!({
+        "id": response["id"],
+        "type": "message",
+        "role": "assistant",
+        "model": response["model"],
+        "content": content,
+        "stop_reason": anthropic_stop_reason(choice["finish_reason"].as_str().unwrap_or_default()),
+        "stop_sequence": null,
+        "usage": {
+            "input_tokens": response["usage"]["prompt_tokens"].as_u64().unwrap_or(0),
+            "output_tokens": response["usage"]["completion_tokens"].as_u64().unwrap_or(0),
+        },
+    })
+}
+
+/// Anthropic `message` to a `chat.completion`.
+fn anthropic_to_openai(response: &Value) -> Value {
+    let mut text = String::new();
+    let mut thinking = String::new();
+    let mut tool_calls = Vec::new();
+    for block in response["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str().unwrap_or_default() {
+            "text" => text.push_str(block["text"].as_str().unwrap_or_default()),
+            "thinking" => thinking.push_str(block["thinking"].as_str().unwrap_or_default()),
+            "tool_use" => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": {"name": block["name"], "arguments": block["input"].to_string()},
+            })),
+            _ => {},
+        }
+    }
+
+    let mut message = json!({"role": "assistant", "content": (!text.is_empty()).then_some(text)});
+    if !thinking.is_empty() {
+        message["reasoning_content"] = json!(thinking);
+    }
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = json!(tool_calls);
+    }
+    let input_tokens = response["usage"]["input_tokens"].as_u64().unwrap_or(0);
+    let output_tokens = response["usage"]["output_tokens"].as_u64().unwrap_or(0);
+    json!({
+        "id": response["id"],
+        "object": "chat.completion",
+        "created": SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        "model": response["model"],
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": openai_finish_reason(response["stop_reason"].as_str().unwrap_or_default()),
+        }],
+        "usage": {
+            "prompt_tokens": input_tokens,
+            "completion_tokens": output_tokens,
+            "total_tokens": input_tokens + output_tokens,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{convert_error, convert_response};
+    use crate::api_format::ApiFormat;
+
+    fn convert(response: Value, from: ApiFormat, to: ApiFormat) -> Value {
+        let converted = convert_response(response.to_string().as_bytes(), from, to).unwrap();
+        serde_json::from_slice(&converted).unwrap()
+    }
+
+    #[test]
+    fn test_openai_to_anthropic() {
+        let response = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": "Checking.",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "weather", "arguments": "{\"city\":\"Paris\"}"},
+                    }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+            "usage": {"prompt_tokens": 20, "completion_tokens": 7, "total_tokens": 27},
+        });
+        let message = convert(response, ApiFormat::OpenAI, ApiFormat::Anthropic);
+
+        assert_eq!(message["type"], "message");
+        assert_eq!(message["model"], "gpt-4o");
+        assert_eq!(message["content"][0], json!({"type": "text", "text": "Checking."}));
+        assert_eq!(message["content"][1]["type"], "tool_use");
+        assert_eq!(message["content"][1]["input"], json!({"city": "Paris"}));
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(message["usage"], json!({"input_tokens": 20, "output_tokens": 7}));
+    }
+
+    #[test]
+    fn test_anthropic_to_openai() {
+        let response = json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4",
+            "content": [{"type": "text", "text": "Hello"}, {"type": "text", "text": " there"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 10, "output_tokens": 3},
+        });
+        let completion = convert(response, ApiFormat::Anthropic, ApiFormat::OpenAI);
+
+        assert_eq!(completion["object"], "chat.completion");
+        assert_eq!(completion["choices"][0]["message"], json!({"role": "assistant", "content": "Hello there"}));
+        assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+        assert_eq!(completion["usage"], json!({"prompt_tokens": 10, "completion_tokens": 3, "total_tokens": 13}));
+    }
+
+    #[test]
+    fn test_convert_error() {
+        let openai = br#"{"error": {"message": "Too many tokens", "type": "invalid_request_error", "param": "messages", "code": "context_length_exceeded"}}"#;
+        let converted: Value = serde_json::from_slice(&convert_error(openai, 400, ApiFormat::OpenAI, ApiFormat::Anthropic)).unwrap();
+        assert_eq!(
+            converted,
+            json!({"type": "error", "error": {"type": "invalid_request_error", "message": "Too many tokens"}})
+        );
+
+        let anthropic = br#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#;
+        let converted: Value = serde_json::from_slice(&convert_error(anthropic, 529, ApiFormat::Anthropic, ApiFormat::OpenAI)).unwrap();
+        assert_eq!(converted["error"]["type"], "overloaded_error");
+        assert_eq!(converted["error"]["message"], "Overloaded");
+
+        let converted: Value = serde_json::from_slice(&convert_error(b"Bad Gateway\n", 502, ApiFormat::OpenAI, ApiFormat::Anthropic)).unwrap();
+        assert_eq!(converted["error"], json!({"type": "api_error", "message": "Bad Gateway"}));
+    }
+}
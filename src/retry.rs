@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+/// How failed upstream attempts are retried. Each retry goes to another
+/// member of the pool, then to the route's next fallback while there is
+/// one, then to the last target again.
+#[derive(clap::Args, Clone, Debug)]
+pub struct RetryPolicy {
+    #[arg(long, help = "Retries after a failed upstream attempt, 0 disables retries", default_value_t = 2, env)]
+    pub max_retries: usize,
+
+    #[arg(long = "retry-backoff-ms", help = "Backoff before the first retry, doubled for each one after", default_value_t = 200, env = "RETRY_BACKOFF_MS")]
+    pub backoff_ms: u64,
+
+    #[arg(long = "retry-max-backoff-ms", help = "Upper bound of the retry backoff, also caps Retry-After", default_value_t = 5000, env = "RETRY_MAX_BACKOFF_MS")]
+    pub max_backoff_ms: u64,
+}
+
+impl RetryPolicy {
+    /// Rate limited, failing or overloaded (Anthropic's 529) upstreams.
+    pub fn retryable_status(status: u16) -> bool {
+        matches!(status, 429 | 500 | 502 | 503 | 529)
+    }
+
+    /// Exponential backoff before the given retry, counted from 1, with
+    /// jitter over its upper half so that clients do not retry in lockstep.
+    /// A `Retry-After` from the upstream replaces it, up to the maximum.
+    pub fn backoff(&self, retry: usize, retry_after: Option<Duration>) -> Duration {
+        let max = Duration::from_millis(self.max_backoff_ms);
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(max);
+        }
+        let exponent = retry.saturating_sub(1).min(16) as u32;
+        let ceiling = self.backoff_ms.saturating_mul(1 << exponent).min(self.max_backoff_ms);
+        Duration::from_millis(rand::random_range(ceiling / 2..=ceiling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            backoff_ms: 100,
+            max_backoff_ms: 1000,
+        };
+        for _ in 0..100 {
+            let first = policy.backoff(1, None);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.backoff(3, None);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            assert!(policy.backoff(10, None) <= Duration::from_millis(1000));
+        }
+        assert_eq!(policy.backoff(1, Some(Duration::from_secs(30))), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(1, Some(Duration::from_millis(300))), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_retryable_status() {
+        assert!(RetryPolicy::retryable_status(429));
+        assert!(RetryPolicy::retryable_status(529));
+        assert!(!RetryPolicy::retryable_status(400));
+        assert!(!RetryPolicy::retryable_status(504));
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::api_format::ApiFormat;
+
+/// A server-sent event.
+#[derive(Debug, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Splits a byte stream into SSE events. Lines split across chunks are
+/// kept until the rest of them arrives.
+#[derive(Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    event: SseEvent,
+    has_data: bool,
+}
+
+impl SseParser {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            self.line(line.trim_end_matches(['\n', '\r']), &mut events);
+        }
+        events
+    }
+
+    /// Dispatches an event left unterminated at the end of the stream.
+    pub fn finish(&mut self) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        if !self.buffer.is_empty() {
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.buffer)).into_owned();
+            self.line(line.trim_end_matches('\r'), &mut events);
+        }
+        self.line("", &mut events);
+        events
+    }
+
+    fn line(&mut self, line: &str, events: &mut Vec<SseEvent>) {
+        if line.is_empty() {
+            if self.has_data {
+                self.has_data = false;
+                events.push(std::mem::take(&mut self.event));
+            }
+            return;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event.event = Some(value.to_string()),
+            "data" => {
+                if self.has_data {
+                    self.event.data.push('\n');
+                }
+                self.event.data.push_str(value);
+                self.has_data = true;
+            },
+            // Comments, ids and retry hints are not needed
+            _ => {},
+        }
+    }
+}
+
+fn sse(event: Option<&str>, data: &str) -> String {
+    match event {
+        Some(event) => format!("event: {}\ndata: {}\n\n", event, data),
+        None => format!("data: {}\n\n", data),
+    }
+}
+
+pub(crate) fn anthropic_stop_reason(finish_reason: &str) -> &'static str {
+    match finish_reason {
+        "length" => "max_tokens",
+        "tool_calls" | "function_call" => "tool_use",
+        "content_filter" => "refusal",
+        _ => "end_turn",
+    }
+}
+
+pub(crate) fn openai_finish_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "refusal" => "content_filter",
+        _ => "stop",
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    Text,
+    Thinking,
+    /// Tool call with its OpenAI index
+    Tool(u64),
+}
+
+/// Turns OpenAI `chat.completion.chunk` events into Anthropic message events.
+#[derive(Default)]
+struct OpenAIToAnthropic {
+    started: bool,
+    finished: bool,
+    /// Index and kind of the open content block
+    block: Option<(usize, Block)>,
+    blocks: usize,
+    stop_reason: Option<&'static str>,
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl OpenAIToAnthropic {
+    fn event(out: &mut String, data: Value) {
+        let kind = data["type"].as_str().unwrap_or_default().to_string();
+        out.push_str(&sse(Some(&kind), &data.to_string()));
+    }
+
+    fn close_block(&mut self, out: &mut String) {
+        if let Some((index, _)) = self.block.take() {
+            Self::event(out, json!({"type": "content_block_stop", "index": index}));
+        }
+    }
+
+    /// Makes `block` the open content block, starting it if needed.
+    fn open_block(&mut self, out: &mut String, block: Block, content_block: impl FnOnce() -> Value) -> usize {
+        if let Some((index, open)) = self.block
+            && open == block
+        {
+            return index;
+        }
+        self.close_block(out);
+        let index = self.blocks;
+        self.blocks += 1;
+        self.block = Some((index, block));
+        Self::event(out, json!({"type": "content_block_start", "index": index, "content_block": content_block()}));
+        index
+    }
+
+    fn convert(&mut self, event: &SseEvent, out: &mut String) {
+        if self.finished {
+            return;
+        }
+        if event.data.trim() == "[DONE]" {
+            self.finish(out);
+            return;
+        }
+        let Ok(chunk) = serde_json::from_str::<Value>(&event.data) else {
+            return;
+        };
+        if let Some(error) = chunk.get("error") {
+            let message = error["message"].as_str().unwrap_or("Upstream error");
+            Self::event(out, json!({"type": "error", "error": {"type": "api_error", "message": message}}));
+            return;
+        }
+
+        if !self.started {
+            self.started = true;
+            Self::event(out, json!({
+                "type": "message_start",
+                "message": {
+                    "id": chunk["id"].as_str().unwrap_or("msg_gateway"),
+                    "type": "message",
+                    "role": "assistant",
+                    "model": chunk["model"].as_str().unwrap_or_default(),
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": {"input_tokens": self.input_tokens, "output_tokens": 0},
+                },
+            }));
+        }
+        if let Some(usage) = chunk.get("usage").filter(|usage| usage.is_object()) {
+            self.input_tokens = usage["prompt_tokens"].as_u64().unwrap_or(self.input_tokens);
+            self.output_tokens = usage["completion_tokens"].as_u64().unwrap_or(self.output_tokens);
+        }
+
+        let Some(choice) = chunk["choices"].as_array().and_then(|choices| choices.first()) else {
+            return;
+        };
+        let delta = &choice["delta"];
+        if let Some(thinking) = delta["reasoning_content"].as_str().filter(|t| !t.is_empty()) {
+            let index = self.open_block(out, Block::Thinking, || json!({"type": "thinking", "thinking": ""}));
+            Self::event(out, json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "thinking_delta", "thinking": thinking},
+            }));
+        }
+        if let Some(text) = delta["content"].as_str().filter(|t| !t.is_empty()) {
+            let index = self.open_block(out, Block::Text, || json!({"type": "text", "text": ""}));
+            Self::event(out, json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "text_delta", "text": text},
+            }));
+        }
+        for tool_call in delta["tool_calls"].as_array().into_iter().flatten() {
+            let tool = Block::Tool(tool_call["index"].as_u64().unwrap_or(0));
+            let index = self.open_block(out, tool, || json!({
+                "type": "tool_use",
+                "id": tool_call["id"].as_str().unwrap_or_default(),
+                "name": tool_call["function"]["name"].as_str().unwrap_or_default(),
+                "input": {},
+            }));
+            if let Some(arguments) = tool_call["function"]["arguments"].as_str().filter(|a| !a.is_empty()) {
+                Self::event(out, json!({
+                    "type": "content_block_delta",
+                    "index": index,
+                    "delta": {"type": "input_json_delta", "partial_json": arguments},
+                }));
+            }
+        }
+        if let Some(finish_reason) = choice["finish_reason"].as_str() {
+            self.stop_reason = Some(anthropic_stop_reason(finish_reason));
+        }
+    }
+
+    /// Usage arrives after the finish reason, so the message is only closed
+    /// once the stream is over.
+    fn finish(&mut self, out: &mut String) {
+        if !self.started || self.finished {
+            return;
+        }
+        self.finished = true;
+        self.close_block(out);
+        Self::event(out, json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": self.stop_reason.unwrap_or("end_turn"), "stop_sequence": null},
+            "usage": {"input_tokens": self.input_tokens, "output_tokens": self.output_tokens},
+        }));
+        Self::event(out, json!({"type": "message_stop"}));
+    }
+}
+
+/// Turns Anthropic message events into OpenAI `chat.completion.chunk` events.
+#[derive(Default)]
+struct AnthropicToOpenAI {
+    id: String,
+    model: String,
+    created: u64,
+    started: bool,
+    finished: bool,
+    /// OpenAI tool call index of each tool use content block
+    tools: HashMap<u64, usize>,
+    finish_reason: Option<&'static str>,
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl AnthropicToOpenAI {
+    fn chunk(&self, out: &mut String, delta: Value, finish_reason: Option<&str>) {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        });
+        out.push_str(&sse(None, &chunk.to_string()));
+    }
+
+    fn convert(&mut self, event: &SseEvent, out: &mut String) {
+        if self.finished {
+            return;
+        }
+        let Ok(data) = serde_json::from_str::<Value>(&event.data) else {
+            return;
+        };
+        match data["type"].as_str().unwrap_or_default() {
+            "message_start" => {
+                let message = &data["message"];
+                self.started = true;
+                self.id = message["id"].as_str().unwrap_or("chatcmpl-gateway").to_string();
+                self.model = message["model"].as_str().unwrap_or_default().to_string();
+                self.created = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                self.input_tokens = message["usage"]["input_tokens"].as_u64().unwrap_or(0);
+                self.chunk(out, json!({"role": "assistant", "content": ""}), None);
+            },
+            "content_block_start" if data["content_block"]["type"] == "tool_use" => {
+                let tool_index = self.tools.len();
+                self.tools.insert(data["index"].as_u64().unwrap_or(0), tool_index);
+                let block = &data["content_block"];
+                self.chunk(out, json!({"tool_calls": [{
+                    "index": tool_index,
+                    "id": block["id"],
+                    "type": "function",
+                    "function": {"name": block["name"], "arguments": ""},
+                }]}), None);
+            },
+            "content_block_delta" => {
+                let delta = &data["delta"];
+                match delta["type"].as_str().unwrap_or_default() {
+                    "text_delta" => self.chunk(out, json!({"content": delta["text"]}), None),
+                    "thinking_delta" => self.chunk(out, json!({"reasoning_content": delta["thinking"]}), None),
+                    "input_json_delta" => {
+                        let tool_index = self.tools.get(&data["index"].as_u64().unwrap_or(0)).copied().unwrap_or(0);
+                        self.chunk(out, json!({"tool_calls": [{
+                            "index": tool_index,
+                            "function": {"arguments": delta["partial_json"]},
+                        }]}), None);
+                    },
+                    _ => {},
+                }
+            },
+            "message_delta" => {
+                if let Some(stop_reason) = data["delta"]["stop_reason"].as_str() {
+                    self.finish_reason = Some(openai_finish_reason(stop_reason));
+                }
+                let usage = &data["usage"];
+                self.input_tokens = usage["input_tokens"].as_u64().unwrap_or(self.input_tokens);
+                self.output_tokens = usage["output_tokens"].as_u64().unwrap_or(self.output_tokens);
+            },
+            "message_stop" => self.finish(out),
+            "error" => {
+                let error = json!({"error": {
+                    "message": data["error"]["message"],
+                    "type": data["error"]["type"],
+                }});
+                out.push_str(&sse(None, &error.to_string()));
+            },
+            // ping, content_block_stop and text or thinking block starts
+            _ => {},
+        }
+    }
+
+    fn finish(&mut self, out: &mut String) {
+        if !self.started || self.finished {
+            return;
+        }
+        self.finished = true;
+        self.chunk(out, json!({}), Some(self.finish_reason.unwrap_or("stop")));
+        let usage = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [],
+            "usage": {
+                "prompt_tokens": self.input_tokens,
+                "completion_tokens": self.output_tokens,
+                "total_tokens": self.input_tokens + self.output_tokens,
+            },
+        });
+        out.push_str(&sse(None, &usage.to_string()));
+        out.push_str(&sse(None, "[DONE]"));
+    }
+}
+
+enum Converter {
+    OpenAIToAnthropic(OpenAIToAnthropic),
+    AnthropicToOpenAI(AnthropicToOpenAI),
+}
+
+/// Converts a streamed chat response chunk by chunk, preserving the order
+/// of its events.
+pub struct StreamConverter {
+    parser: SseParser,
+    converter: Converter,
+}
+
+impl StreamConverter {
+    /// `None` when both sides speak the same format. OpenAI only reports
+    /// usage once the stream is over, so Anthropic's `message_start` reports
+    /// `prompt_tokens` as counted by the gateway.
+    pub fn new(from: ApiFormat, to: ApiFormat, prompt_tokens: u64) -> Option<Self> {
+        let converter = match (from, to) {
+            (ApiFormat::OpenAI, ApiFormat::Anthropic) => Converter::OpenAIToAnthropic(OpenAIToAnthropic {
+                input_tokens: prompt_tokens,
+                ..Default::default()
+            }),
+            (ApiFormat::Anthropic, ApiFormat::OpenAI) => Converter::AnthropicToOpenAI(AnthropicToOpenAI::default()),
+            _ => return None,
+        };
+        Some(Self {
+            parser: SseParser::default(),
+            converter,
+        })
+    }
+
+    fn convert(&mut self, events: Vec<SseEvent>, out: &mut String) {
+        for event in &events {
+            match &mut self.converter {
+                Converter::OpenAIToAnthropic(converter) => converter.convert(event, out),
+                Converter::AnthropicToOpenAI(converter) => converter.convert(event, out),
+            }
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut out = String::new();
+        let events = self.parser.push(chunk);
+        self.convert(events, &mut out);
+        out.into_bytes()
+    }
+
+    /// Flushes what is left once the upstream stream is over, closing the
+    /// message if the upstream did not.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut out = String::new();
+        let events = self.parser.finish();
+        self.convert(events, &mut out);
+        match &mut self.converter {
+            Converter::OpenAIToAnthropic(converter) => converter.finish(&mut out),
+            Converter::AnthropicToOpenAI(converter) => converter.finish(&mut out),
+        }
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{SseEvent, SseParser, StreamConverter};
+    use crate::api_format::ApiFormat;
+
+    /// Feeds `input` a few bytes at a time and parses what comes out.
+    fn convert(from: ApiFormat, to: ApiFormat, input: &str) -> Vec<SseEvent> {
+        let mut converter = StreamConverter::new(from, to, 9).unwrap();
+        let mut output = Vec::new();
+        for chunk in input.as_bytes().chunks(7) {
+            output.extend(converter.push(chunk));
+        }
+        output.extend(converter.finish());
+
+        let mut parser = SseParser::default();
+        let mut events = parser.push(&output);
+        events.extend(parser.finish());
+        events
+    }
+
+    fn data(event: &SseEvent) -> Value {
+        serde_json::from_str(&event.data).unwrap()
+    }
+
+    #[test]
+    fn test_sse_parser_handles_split_lines() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b"event: ping\r\nda").is_empty());
+        assert!(parser.push(b"ta: {\"a\":").is_empty());
+        let events = parser.push(b" 1}\r\n\r\n: comment\n\ndata: [DONE]");
+        assert_eq!(events, vec![SseEvent { event: Some("ping".to_string()), data: "{\"a\": 1}".to_string() }]);
+        assert_eq!(parser.finish(), vec![SseEvent { event: None, data: "[DONE]".to_string() }]);
+    }
+
+    #[test]
+    fn test_openai_to_anthropic() {
+        let input = concat!(
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o\",\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":2}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let events = convert(ApiFormat::OpenAI, ApiFormat::Anthropic, input);
+
+        let kinds: Vec<&str> = events.iter().map(|e| e.event.as_deref().unwrap()).collect();
+        assert_eq!(kinds, vec![
+            "message_start",
+            "content_block_start",
+            "content_block_delta",
+            "content_block_delta",
+            "content_block_stop",
+            "message_delta",
+            "message_stop",
+        ]);
+        assert_eq!(data(&events[0])["message"]["model"], "gpt-4o");
+        assert_eq!(data(&events[0])["message"]["usage"]["input_tokens"], 9);
+        let text: String = events[2..4].iter().map(|e| data(e)["delta"]["text"].as_str().unwrap().to_string()).collect();
+        assert_eq!(text, "Hello");
+        let message_delta = data(&events[5]);
+        assert_eq!(message_delta["delta"]["stop_reason"], "end_turn");
+        assert_eq!(message_delta["usage"]["output_tokens"], 2);
+        assert_eq!(message_delta["usage"]["input_tokens"], 9);
+    }
+
+    #[test]
+    fn test_openai_tool_calls_to_anthropic() {
+        let input = concat!(
+            "data: {\"id\":\"c\",\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Let me check.\"}}]}\n\n",
+            "data: {\"id\":\"c\",\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"function\":{\"name\":\"weather\",\"arguments\":\"\"}}]}}]}\n\n",
+            "data: {\"id\":\"c\",\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"city\\\":\"}}]}}]}\n\n",
+            "data: {\"id\":\"c\",\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"Paris\\\"}\"}}]}}]}\n\n",
+            "data: {\"id\":\"c\",\"model\":\"m\",\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\n",
+        );
+        // No [DONE]: the message is closed when the stream ends
+        let events = convert(ApiFormat::OpenAI, ApiFormat::Anthropic, input);
+
+        let tool_start = events.iter().map(data).find(|d| d["content_block"]["type"] == "tool_use").unwrap();
+        assert_eq!(tool_start["index"], 1);
+        assert_eq!(tool_start["content_block"]["name"], "weather");
+        let arguments: String = events
+            .iter()
+            .map(data)
+            .filter(|d| d["delta"]["type"] == "input_json_delta")
+            .map(|d| d["delta"]["partial_json"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(arguments, "{\"city\":\"Paris\"}");
+        assert_eq!(data(&events[events.len() - 2])["delta"]["stop_reason"], "tool_use");
+        assert_eq!(events.last().unwrap().event.as_deref(), Some("message_stop"));
+    }
+
+    #[test]
+    fn test_anthropic_to_openai() {
+        let input = concat!(
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"model\":\"claude-sonnet-4\",\"usage\":{\"input_tokens\":12,\"output_tokens\":1}}}\n\n",
+            "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "event: ping\ndata: {\"type\":\"ping\"}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"max_tokens\"},\"usage\":{\"output_tokens\":5}}\n\n",
+            "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+        );
+        let events = convert(ApiFormat::Anthropic, ApiFormat::OpenAI, input);
+
+        assert_eq!(events.len(), 5);
+        assert_eq!(data(&events[0])["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(data(&events[1])["choices"][0]["delta"]["content"], "Hi");
+        assert_eq!(data(&events[2])["choices"][0]["finish_reason"], "length");
+        let usage = data(&events[3]);
+        assert_eq!(usage["usage"]["prompt_tokens"], 12);
+        assert_eq!(usage["usage"]["completion_tokens"], 5);
+        assert_eq!(events[4].data, "[DONE]");
+    }
+}
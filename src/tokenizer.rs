@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use tiktoken_rs::{CoreBPE, Rank};
+
+use crate::pattern;
+
+/// Split pattern of cl100k_base, shared by most tiktoken-style vocabularies
+const CL100K_PATTERN: &str = "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+";
+/// Average characters per token of English text in BPE vocabularies
+const CHARS_PER_TOKEN: usize = 4;
+
+#[derive(Deserialize, clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// GPT-4o, GPT-4.1, o1, o3 and later
+    #[value(name = "o200k_base")]
+    O200kBase,
+    /// GPT-4, GPT-3.5 and the text-embedding models
+    #[value(name = "cl100k_base")]
+    Cl100kBase,
+    /// Codex and text-davinci-002/003
+    #[value(name = "p50k_base")]
+    P50kBase,
+    /// GPT-3
+    #[value(name = "r50k_base")]
+    R50kBase,
+    /// No vocabulary, about four characters per token
+    #[value(name = "characters")]
+    Characters,
+}
+
+/// Counts the tokens of a text the way a model's tokenizer would.
+#[derive(Clone, Copy)]
+pub enum Tokenizer {
+    Bpe(&'static CoreBPE),
+    Characters,
+}
+
+impl Tokenizer {
+    fn from_encoding(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::O200kBase => Tokenizer::Bpe(tiktoken_rs::o200k_base_singleton()),
+            Encoding::Cl100kBase => Tokenizer::Bpe(tiktoken_rs::cl100k_base_singleton()),
+            Encoding::P50kBase => Tokenizer::Bpe(tiktoken_rs::p50k_base_singleton()),
+            Encoding::R50kBase => Tokenizer::Bpe(tiktoken_rs::r50k_base_singleton()),
+            Encoding::Characters => Tokenizer::Characters,
+        }
+    }
+
+    /// Loads a vocabulary in the `.tiktoken` format, a base64 token and its
+    /// rank per line.
+    fn load(path: &str, split_pattern: Option<&str>) -> Result<Self> {
+        let file = std::fs::read_to_string(path).with_context(|| format!("Failed to open {}", path))?;
+        // CoreBPE panics on a rank given to several tokens
+        let mut ranks: HashSet<Rank> = HashSet::new();
+        let encoder = file
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(index, line)| {
+                let (token, rank) = line
+                    .split_once(' ')
+                    .with_context(|| format!("Line {} of {} is not a token and a rank", index + 1, path))?;
+                let token = general_purpose::STANDARD
+                    .decode(token)
+                    .with_context(|| format!("Invalid token on line {} of {}", index + 1, path))?;
+                let rank = rank
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid rank on line {} of {}", index + 1, path))?;
+                if !ranks.insert(rank) {
+                    bail!("Rank {} on line {} of {} is given to another token", rank, index + 1, path);
+                }
+                Ok((token, rank))
+            })
+            .collect::<Result<_>>()?;
+        let bpe = CoreBPE::new(encoder, Default::default(), split_pattern.unwrap_or(CL100K_PATTERN))
+            .with_context(|| format!("Failed to build the tokenizer of {}", path))?;
+        // Loaded once at startup and used for the lifetime of the process
+        Ok(Tokenizer::Bpe(Box::leak(Box::new(bpe))))
+    }
+
+    /// Whether this is the encoding of GPT-4o and later models.
+    pub fn is_o200k(&self) -> bool {
+        matches!(self, Tokenizer::Bpe(bpe) if std::ptr::eq(*bpe, tiktoken_rs::o200k_base_singleton()))
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        match self {
+            Tokenizer::Bpe(bpe) => bpe.encode_with_special_tokens(text).len(),
+            Tokenizer::Characters => text.chars().count().div_ceil(CHARS_PER_TOKEN),
+        }
+    }
+}
+
+// Tokenizer file format
+#[derive(Deserialize, Debug)]
+struct TokenizerFile {
+    tokenizers: Vec<TokenizerEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenizerEntry {
+    /// Model name pattern
+    model: String,
+    #[serde(default)]
+    encoding: Option<Encoding>,
+    /// Vocabulary in the `.tiktoken` format
+    #[serde(default)]
+    file: Option<String>,
+    /// Regex splitting text before the vocabulary applies, defaults to the
+    /// one of cl100k_base
+    #[serde(default)]
+    pattern: Option<String>,
+}
+
+/// Tokenizers by model name pattern. The first matching pattern wins, then
+/// the encoding tiktoken knows for OpenAI models, then the fallback.
+pub struct TokenizerRegistry {
+    tokenizers: Vec<(String, Tokenizer)>,
+    fallback: Tokenizer,
+}
+
+impl TokenizerRegistry {
+    pub fn new(fallback: Encoding) -> Self {
+        Self {
+            tokenizers: Vec::new(),
+            fallback: Tokenizer::from_encoding(fallback),
+        }
+    }
+
+    pub fn load(path: &str, fallback: Encoding) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let tokenizer_file: TokenizerFile = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path))?;
+        Self::from_file(tokenizer_file, fallback).with_context(|| format!("Invalid tokenizers {}", path))
+    }
+
+    fn from_file(tokenizer_file: TokenizerFile, fallback: Encoding) -> Result<Self> {
+        let mut registry = Self::new(fallback);
+        for entry in tokenizer_file.tokenizers {
+            let tokenizer = match (entry.encoding, &entry.file) {
+                (Some(encoding), None) => Tokenizer::from_encoding(encoding),
+                (None, Some(file)) => Tokenizer::load(file, entry.pattern.as_deref())?,
+                _ => bail!("Tokenizer for {} needs either an encoding or a file", entry.model),
+            };
+            registry.tokenizers.push((entry.model, tokenizer));
+        }
+        Ok(registry)
+    }
+
+    pub fn for_model(&self, model: &str) -> Tokenizer {
+        if let Some((_, tokenizer)) = self.tokenizers.iter().find(|(pattern, _)| pattern::matches(pattern, model)) {
+            return *tokenizer;
+        }
+        let encoding = tiktoken_rs::tokenizer::get_tokenizer(model).map(|tokenizer| {
+            use tiktoken_rs::tokenizer::Tokenizer as Known;
+            match tokenizer {
+                Known::O200kBase => Encoding::O200kBase,
+                Known::Cl100kBase => Encoding::Cl100kBase,
+                Known::P50kBase | Known::P50kEdit => Encoding::P50kBase,
+                Known::R50kBase | Known::Gpt2 => Encoding::R50kBase,
+            }
+        });
+        encoding.map_or(self.fallback, Tokenizer::from_encoding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoding, Tokenizer, TokenizerFile, TokenizerRegistry};
+
+    fn bpe(tokenizer: Tokenizer) -> *const tiktoken_rs::CoreBPE {
+        match tokenizer {
+            Tokenizer::Bpe(bpe) => bpe,
+            Tokenizer::Characters => std::ptr::null(),
+        }
+    }
+
+    #[test]
+    fn test_for_model() {
+        let tokenizer_file: TokenizerFile = serde_json::from_str(
+            r#"{"tokenizers": [
+                {"model": "gpt-4o-legacy*", "encoding": "cl100k_base"},
+                {"model": "llama*", "encoding": "characters"}
+            ]}"#,
+        )
+        .unwrap();
+        let registry = TokenizerRegistry::from_file(tokenizer_file, Encoding::Cl100kBase).unwrap();
+        let o200k = tiktoken_rs::o200k_base_singleton() as *const _;
+        let cl100k = tiktoken_rs::cl100k_base_singleton() as *const _;
+
+        assert_eq!(bpe(registry.for_model("gpt-4o-2024-08-06")), o200k);
+        assert_eq!(bpe(registry.for_model("o3-mini")), o200k);
+        assert_eq!(bpe(registry.for_model("gpt-4o-legacy-test")), cl100k);
+        assert_eq!(bpe(registry.for_model("gpt-3.5-turbo")), cl100k);
+        assert!(bpe(registry.for_model("llama-3.1-8b")).is_null());
+        // Unknown models use the fallback
+        assert_eq!(bpe(registry.for_model("mistral-large")), cl100k);
+
+        let tokenizer_file: TokenizerFile = serde_json::from_str(r#"{"tokenizers": [{"model": "x"}]}"#).unwrap();
+        assert!(TokenizerRegistry::from_file(tokenizer_file, Encoding::Characters).is_err());
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(Tokenizer::from_encoding(Encoding::O200kBase).count("Hello world"), 2);
+        assert_eq!(Tokenizer::Characters.count("Hello world"), 3);
+        assert_eq!(Tokenizer::Characters.count(""), 0);
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("tokenizer-{}.tiktoken", std::process::id()));
+        // "a", "b" and "ab"
+        std::fs::write(&path, "YQ== 0\nYg== 1\nYWI= 2\n").unwrap();
+        let tokenizer = Tokenizer::load(path.to_str().unwrap(), None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tokenizer.count("ab"), 1);
+        assert_eq!(tokenizer.count("abba"), 3);
+
+        std::fs::write(&path, "YQ== 0\nYg== 1\nYWI= 1\n").unwrap();
+        let error = Tokenizer::load(path.to_str().unwrap(), None).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("Rank 1 on line 3"));
+    }
+}
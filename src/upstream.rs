@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::api_format::ApiFormat;
+use crate::balancer::{Member, Pool, Strategy};
+use crate::pattern;
+
+/// Credentials the gateway sends upstream in place of the client's own.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UpstreamAuth {
+    /// `Authorization: Bearer <token>`
+    Bearer { token: String },
+    /// Any other header, e.g. `api-key` for Azure or `x-api-key` for Anthropic
+    Header { name: String, value: String },
+}
+
+impl UpstreamAuth {
+    /// Values written as `env:NAME` are read from the environment so that
+    /// secrets stay out of the routing file.
+    fn resolve(self) -> Result<Self> {
+        let resolve = |value: String| match value.strip_prefix("env:") {
+            Some(name) => std::env::var(name).with_context(|| format!("Environment variable {} is not set", name)),
+            None => Ok(value),
+        };
+        Ok(match self {
+            UpstreamAuth::Bearer { token } => UpstreamAuth::Bearer { token: resolve(token)? },
+            UpstreamAuth::Header { name, value } => UpstreamAuth::Header { name, value: resolve(value)? },
+        })
+    }
+
+    pub fn header(&self) -> (&str, String) {
+        match self {
+            UpstreamAuth::Bearer { token } => ("Authorization", format!("Bearer {}", token)),
+            UpstreamAuth::Header { name, value } => (name, value.clone()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Upstream {
+    #[serde(skip)]
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+    /// Defaults to 443 with TLS and 80 without
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Server name for TLS, defaults to `host`
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// Replaces the `/v1` prefix of request paths, e.g.
+    /// `/openai/deployments/gpt-4o` for Azure OpenAI
+    #[serde(default = "default_base_path")]
+    pub base_path: String,
+    /// Query string added to every request, e.g. `api-version=2024-10-21`
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub auth: Option<UpstreamAuth>,
+    /// Chat requests in the other format are converted to this one
+    #[serde(default)]
+    pub format: ApiFormat,
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+fn default_base_path() -> String {
+    "/v1".to_string()
+}
+
+impl Upstream {
+    pub fn new(name: &str, host: &str, port: u16, tls: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            host: host.to_string(),
+            tls,
+            port: Some(port),
+            sni: None,
+            base_path: default_base_path(),
+            query: None,
+            auth: None,
+            format: ApiFormat::OpenAI,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.tls { 443 } else { 80 })
+    }
+
+    pub fn sni(&self) -> &str {
+        self.sni.as_deref().unwrap_or(&self.host)
+    }
+
+    /// Value of the `Host` header, with the port only when it is not the
+    /// scheme's default.
+    pub fn authority(&self) -> String {
+        match (self.tls, self.port()) {
+            (true, 443) | (false, 80) => self.host.clone(),
+            (_, port) => format!("{}:{}", self.host, port),
+        }
+    }
+
+    /// Maps a client path and query onto this upstream. A leading `/v1`
+    /// segment is replaced by the base path, `/v1beta` and the like are not.
+    pub fn path_and_query(&self, path: &str, query: Option<&str>) -> String {
+        let rest = path
+            .strip_prefix("/v1")
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(path);
+        let mut path_and_query = format!("{}{}", self.base_path.trim_end_matches('/'), rest);
+        let query: Vec<&str> = query.into_iter().chain(self.query.as_deref()).filter(|q| !q.is_empty()).collect();
+        if !query.is_empty() {
+            path_and_query.push('?');
+            path_and_query.push_str(&query.join("&"));
+        }
+        path_and_query
+    }
+}
+
+// Routing file format
+#[derive(Deserialize, Debug)]
+struct RoutingFile {
+    upstreams: HashMap<String, Upstream>,
+    #[serde(default)]
+    pools: HashMap<String, PoolEntry>,
+    routes: Vec<RouteEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PoolEntry {
+    #[serde(default)]
+    strategy: Strategy,
+    members: Vec<MemberEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MemberEntry {
+    upstream: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+struct RouteEntry {
+    /// Model name pattern; absent matches every request, even those whose
+    /// model is unknown
+    #[serde(default)]
+    model: Option<String>,
+    /// Request path pattern
+    #[serde(default)]
+    path: Option<String>,
+    /// Upstream or pool name
+    upstream: String,
+    /// Tried in order when the upstream fails
+    #[serde(default)]
+    fallbacks: Vec<FallbackEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FallbackEntry {
+    upstream: String,
+    /// Replaces the requested model
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// Upstreams a request can be sent to, with the model to ask them for when
+/// it differs from the requested one.
+#[derive(Clone)]
+pub struct Target {
+    pub pool: Arc<Pool>,
+    pub model: Option<String>,
+}
+
+struct Route {
+    model: Option<String>,
+    path: Option<String>,
+    /// The route's upstream followed by its fallbacks
+    targets: Vec<Target>,
+}
+
+/// Ordered routes from model and path patterns to named upstreams. The first
+/// matching route wins.
+pub struct RoutingTable {
+    routes: Vec<Route>,
+    /// Pools by name, including the pool of one of every upstream
+    pools: HashMap<String, Arc<Pool>>,
+}
+
+impl RoutingTable {
+    /// A table sending everything to `upstream`.
+    pub fn single(upstream: Upstream) -> Self {
+        let name = upstream.name.clone();
+        let pool = Arc::new(Pool::single(Arc::new(upstream)));
+        Self {
+            routes: vec![Route {
+                model: None,
+                path: None,
+                targets: vec![Target {
+                    pool: pool.clone(),
+                    model: None,
+                }],
+            }],
+            pools: HashMap::from([(name, pool)]),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let routing_file: RoutingFile = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path))?;
+        Self::from_file(routing_file).with_context(|| format!("Invalid routing table {}", path))
+    }
+
+    fn from_file(routing_file: RoutingFile) -> Result<Self> {
+        let mut upstreams = HashMap::new();
+        for (name, mut upstream) in routing_file.upstreams {
+            upstream.name = name.clone();
+            upstream.auth = upstream.auth.map(UpstreamAuth::resolve).transpose()?;
+            upstreams.insert(name, Arc::new(upstream));
+        }
+
+        // Routes name an upstream, which is a pool of one, or a pool
+        let mut pools = HashMap::new();
+        for (name, entry) in routing_file.pools {
+            if upstreams.contains_key(&name) {
+                bail!("Pool {} has the name of an upstream", name);
+            }
+            let mut members = Vec::new();
+            for member in entry.members {
+                let Some(upstream) = upstreams.get(&member.upstream) else {
+                    bail!("Pool {} has unknown upstream {}", name, member.upstream);
+                };
+                members.push(Member {
+                    upstream: upstream.clone(),
+                    weight: member.weight,
+                });
+            }
+            if members.is_empty() {
+                bail!("Pool {} has no members", name);
+            }
+            pools.insert(name.clone(), Arc::new(Pool::new(&name, entry.strategy, members)));
+        }
+        for (name, upstream) in &upstreams {
+            pools.insert(name.clone(), Arc::new(Pool::single(upstream.clone())));
+        }
+
+        let target = |name: &str, model: Option<String>| match pools.get(name) {
+            Some(pool) => Ok(Target {
+                pool: pool.clone(),
+                model,
+            }),
+            None => Err(anyhow!("Route to unknown upstream {}", name)),
+        };
+        let mut routes = Vec::new();
+        for entry in routing_file.routes {
+            let mut targets = vec![target(&entry.upstream, None)?];
+            for fallback in entry.fallbacks {
+                targets.push(target(&fallback.upstream, fallback.model)?);
+            }
+            routes.push(Route {
+                model: entry.model,
+                path: entry.path,
+                targets,
+            });
+        }
+        Ok(Self { routes, pools })
+    }
+
+    /// Whether any route depends on the model, which means the request body
+    /// has to be read before an upstream can be picked.
+    pub fn routes_by_model(&self) -> bool {
+        self.routes.iter().any(|route| route.model.is_some())
+    }
+
+    /// Upstream or pool by name.
+    pub fn pool(&self, name: &str) -> Option<Arc<Pool>> {
+        self.pools.get(name).cloned()
+    }
+
+    /// Every upstream, by name.
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        let mut upstreams: Vec<Arc<Upstream>> = Vec::new();
+        for pool in self.pools.values() {
+            for member in 0..pool.member_count() {
+                let upstream = pool.upstream(member);
+                if !upstreams.iter().any(|known| known.name == upstream.name) {
+                    upstreams.push(upstream.clone());
+                }
+            }
+        }
+        upstreams.sort_by(|a, b| a.name.cmp(&b.name));
+        upstreams
+    }
+
+    /// Targets of the first matching route, its upstream first.
+    pub fn route(&self, path: &str, model: Option<&str>) -> Option<&[Target]> {
+        self.routes
+            .iter()
+            .find(|route| {
+                let model_matches = match (&route.model, model) {
+                    (None, _) => true,
+                    (Some(pattern), Some(model)) => pattern::matches(pattern, model),
+                    (Some(_), None) => false,
+                };
+                model_matches && route.path.as_ref().is_none_or(|p| pattern::matches(p, path))
+            })
+            .map(|route| route.targets.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoutingFile, RoutingTable, UpstreamAuth};
+    use crate::api_format::ApiFormat;
+
+    fn routing_table() -> RoutingTable {
+        let routing_file: RoutingFile = serde_json::from_str(
+            r#"{
+                "upstreams": {
+                    "openai": {"host": "api.openai.com", "auth": {"type": "bearer", "token": "sk-test"}},
+                    "azure": {
+                        "host": "example.openai.azure.com",
+                        "base_path": "/openai/deployments/gpt-4o",
+                        "query": "api-version=2024-10-21",
+                        "auth": {"type": "header", "name": "api-key", "value": "azure-key"}
+                    },
+                    "vllm": {"host": "127.0.0.1", "tls": false, "port": 8000},
+                    "anthropic": {"host": "api.anthropic.com", "format": "anthropic"},
+                    "east": {"host": "east.openai.azure.com"},
+                    "west": {"host": "west.openai.azure.com"}
+                },
+                "pools": {
+                    "gpt-4o-mini": {
+                        "strategy": "least_outstanding_tokens",
+                        "members": [{"upstream": "east", "weight": 2}, {"upstream": "west"}]
+                    }
+                },
+                "routes": [
+                    {"model": "gpt-4o", "upstream": "azure"},
+                    {"model": "gpt-4o-mini", "upstream": "gpt-4o-mini"},
+                    {"model": "llama*", "path": "/v1/chat/*", "upstream": "vllm"},
+                    {"model": "claude-*", "upstream": "anthropic", "fallbacks": [{"upstream": "openai", "model": "gpt-4o"}]},
+                    {"upstream": "openai"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        RoutingTable::from_file(routing_file).unwrap()
+    }
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let table = routing_table();
+        assert!(table.routes_by_model());
+
+        let route = |path, model| table.route(path, model).unwrap()[0].pool.name.clone();
+        assert_eq!(route("/v1/chat/completions", Some("gpt-4o")), "azure");
+        assert_eq!(route("/v1/chat/completions", Some("gpt-4o-mini")), "gpt-4o-mini");
+        assert_eq!(route("/v1/chat/completions", Some("gpt-4o-2024-08-06")), "openai");
+        assert_eq!(route("/v1/chat/completions", Some("llama-3.1-8b")), "vllm");
+        assert_eq!(route("/v1/embeddings", Some("llama-3.1-8b")), "openai");
+        assert_eq!(route("/v1/models", None), "openai");
+        assert_eq!(route("/v1/messages", Some("claude-sonnet-4")), "anthropic");
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let table = routing_table();
+        let targets = table.route("/v1/messages", Some("claude-sonnet-4")).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].model, None);
+        assert_eq!(targets[1].pool.name, "openai");
+        assert_eq!(targets[1].model.as_deref(), Some("gpt-4o"));
+
+        let routing_file: RoutingFile = serde_json::from_str(
+            r#"{"upstreams": {"a": {"host": "a"}}, "routes": [{"upstream": "a", "fallbacks": [{"upstream": "b"}]}]}"#,
+        )
+        .unwrap();
+        assert!(RoutingTable::from_file(routing_file).is_err());
+    }
+
+    #[test]
+    fn test_upstream_addressing() {
+        let table = routing_table();
+
+        let upstream = |path, model| table.route(path, model).unwrap()[0].pool.upstream(0).clone();
+        let azure = upstream("/v1/chat/completions", Some("gpt-4o"));
+        assert_eq!(azure.port(), 443);
+        assert_eq!(azure.sni(), "example.openai.azure.com");
+        assert_eq!(azure.authority(), "example.openai.azure.com");
+        assert_eq!(
+            azure.path_and_query("/v1/chat/completions", None),
+            "/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(
+            azure.path_and_query("/v1beta/models", None),
+            "/openai/deployments/gpt-4o/v1beta/models?api-version=2024-10-21"
+        );
+        assert_eq!(
+            azure.path_and_query("/v10/models", None),
+            "/openai/deployments/gpt-4o/v10/models?api-version=2024-10-21"
+        );
+        assert_eq!(azure.path_and_query("/v1", None), "/openai/deployments/gpt-4o?api-version=2024-10-21");
+        assert_eq!(azure.auth.as_ref().unwrap().header(), ("api-key", "azure-key".to_string()));
+
+        let vllm = upstream("/v1/chat/completions", Some("llama3"));
+        assert_eq!(vllm.authority(), "127.0.0.1:8000");
+        assert_eq!(vllm.path_and_query("/v1/chat/completions", Some("a=1")), "/v1/chat/completions?a=1");
+
+        let openai = upstream("/v1/models", None);
+        assert_eq!(openai.auth, Some(UpstreamAuth::Bearer { token: "sk-test".to_string() }));
+        assert_eq!(openai.format, ApiFormat::OpenAI);
+
+        let anthropic = upstream("/v1/messages", Some("claude-opus-4"));
+        assert_eq!(anthropic.format, ApiFormat::Anthropic);
+    }
+
+    #[test]
+    fn test_unknown_upstream_is_rejected() {
+        let routing_file: RoutingFile = serde_json::from_str(
+            r#"{"upstreams": {}, "routes": [{"upstream": "missing"}]}"#,
+        )
+        .unwrap();
+        assert!(RoutingTable::from_file(routing_file).is_err());
+    }
+
+    #[test]
+    fn test_pools() {
+        let table = routing_table();
+        let pool = &table.route("/v1/chat/completions", Some("gpt-4o-mini")).unwrap()[0].pool;
+        assert_eq!(pool.member_count(), 2);
+        assert_eq!(pool.upstream(0).name, "east");
+
+        // Single upstreams are shared by every route naming them
+        let openai = &table.route("/v1/models", None).unwrap()[0].pool;
+        let fallback = &table.route("/v1/messages", Some("claude-sonnet-4")).unwrap()[1].pool;
+        assert!(std::sync::Arc::ptr_eq(openai, fallback));
+
+        let upstreams: Vec<String> = table.upstreams().iter().map(|upstream| upstream.name.clone()).collect();
+        assert_eq!(upstreams, ["anthropic", "azure", "east", "openai", "vllm", "west"]);
+
+        let routing_file: RoutingFile = serde_json::from_str(
+            r#"{"upstreams": {"a": {"host": "a"}}, "pools": {"p": {"members": [{"upstream": "b"}]}}, "routes": []}"#,
+        )
+        .unwrap();
+        assert!(RoutingTable::from_file(routing_file).is_err());
+    }
+}